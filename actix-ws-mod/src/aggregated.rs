@@ -0,0 +1,370 @@
+use actix_http::ws::{CloseCode, CloseReason, Item, Message, ProtocolError};
+use actix_web::web::{Bytes, BytesMut};
+use bytestring::ByteString;
+use futures_core::stream::Stream;
+use std::{
+    convert::TryFrom,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{fut::poll_fn, MessageStream};
+
+/// A complete message from a websocket client, reassembled from continuation frames if the
+/// client fragmented it
+#[derive(Debug, PartialEq, Eq)]
+pub enum AggregatedMessage {
+    /// Text message
+    Text(ByteString),
+
+    /// Binary message
+    Binary(Bytes),
+
+    /// Ping message
+    Ping(Bytes),
+
+    /// Pong message
+    Pong(Bytes),
+
+    /// Close message with optional reason
+    Close(Option<CloseReason>),
+}
+
+enum ContinuationKind {
+    Text,
+    Binary,
+}
+
+/// A stream of complete messages from a websocket client
+///
+/// Created by [`MessageStream::aggregate_continuations`]. Messages larger than the maximum
+/// message size fail with [`ProtocolError::Overflow`] and close the connection.
+pub struct AggregatedMessageStream {
+    stream: MessageStream,
+    continuation: Option<(ContinuationKind, BytesMut)>,
+    max_size: usize,
+}
+
+impl MessageStream {
+    /// Reassemble fragmented messages, instead of yielding their continuation frames
+    ///
+    /// ```rust,no_run
+    /// # use actix_ws::{AggregatedMessage, MessageStream};
+    /// # async fn handle(msg_stream: MessageStream) {
+    /// let mut msg_stream = msg_stream.aggregate_continuations().max_message_size(1 << 20);
+    ///
+    /// while let Some(Ok(msg)) = msg_stream.recv().await {
+    ///     match msg {
+    ///         AggregatedMessage::Text(s) => println!("Got text, {}", s),
+    ///         _ => (),
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    pub fn aggregate_continuations(self) -> AggregatedMessageStream {
+        AggregatedMessageStream {
+            stream: self,
+            continuation: None,
+            max_size: 2 << 20,
+        }
+    }
+}
+
+impl AggregatedMessageStream {
+    /// Set the maximum size of a whole message, 2 MiB by default
+    pub fn max_message_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Wait for the next item from the message stream
+    pub async fn recv(&mut self) -> Option<Result<AggregatedMessage, ProtocolError>> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    fn overflow(&mut self) -> Poll<Option<Result<AggregatedMessage, ProtocolError>>> {
+        self.continuation = None;
+        self.stream.close_with(CloseCode::Size, "message too large");
+        Poll::Ready(Some(Err(ProtocolError::Overflow)))
+    }
+
+    fn protocol_error(
+        &mut self,
+        e: ProtocolError,
+    ) -> Poll<Option<Result<AggregatedMessage, ProtocolError>>> {
+        self.continuation = None;
+        self.stream
+            .close_with(CloseCode::Protocol, "protocol error");
+        Poll::Ready(Some(Err(e)))
+    }
+
+    fn start(
+        &mut self,
+        kind: ContinuationKind,
+        bytes: Bytes,
+    ) -> Option<Poll<Option<Result<AggregatedMessage, ProtocolError>>>> {
+        if self.continuation.is_some() {
+            return Some(self.protocol_error(ProtocolError::ContinuationStarted));
+        }
+        if bytes.len() > self.max_size {
+            return Some(self.overflow());
+        }
+        self.continuation = Some((kind, BytesMut::from(&bytes[..])));
+        None
+    }
+
+    fn append(
+        &mut self,
+        bytes: Bytes,
+    ) -> Option<Poll<Option<Result<AggregatedMessage, ProtocolError>>>> {
+        let len = match &self.continuation {
+            Some((_, buf)) => buf.len(),
+            None => return Some(self.protocol_error(ProtocolError::ContinuationNotStarted)),
+        };
+        if len + bytes.len() > self.max_size {
+            return Some(self.overflow());
+        }
+        if let Some((_, buf)) = &mut self.continuation {
+            buf.extend_from_slice(&bytes);
+        }
+        None
+    }
+
+    fn finish(&mut self) -> Poll<Option<Result<AggregatedMessage, ProtocolError>>> {
+        let (kind, buf) = match self.continuation.take() {
+            Some(continuation) => continuation,
+            None => return self.protocol_error(ProtocolError::ContinuationNotStarted),
+        };
+        match kind {
+            ContinuationKind::Binary => {
+                Poll::Ready(Some(Ok(AggregatedMessage::Binary(buf.freeze()))))
+            }
+            ContinuationKind::Text => match ByteString::try_from(buf.freeze()) {
+                Ok(s) => Poll::Ready(Some(Ok(AggregatedMessage::Text(s)))),
+                Err(e) => {
+                    self.stream.close_with(CloseCode::Invalid, "invalid UTF-8");
+                    Poll::Ready(Some(Err(ProtocolError::Io(io::Error::other(
+                        e.to_string(),
+                    )))))
+                }
+            },
+        }
+    }
+}
+
+impl Stream for AggregatedMessageStream {
+    type Item = Result<AggregatedMessage, ProtocolError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let msg = match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(msg))) => msg,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let early = match msg {
+                // Data frames must not be interleaved with a fragmented message
+                Message::Text(_) | Message::Binary(_) if this.continuation.is_some() => {
+                    return this.protocol_error(ProtocolError::ContinuationStarted);
+                }
+                Message::Text(s) if s.len() > this.max_size => return this.overflow(),
+                Message::Binary(bytes) if bytes.len() > this.max_size => return this.overflow(),
+                Message::Text(s) => return Poll::Ready(Some(Ok(AggregatedMessage::Text(s)))),
+                Message::Binary(bytes) => {
+                    return Poll::Ready(Some(Ok(AggregatedMessage::Binary(bytes))))
+                }
+                Message::Ping(bytes) => {
+                    return Poll::Ready(Some(Ok(AggregatedMessage::Ping(bytes))))
+                }
+                Message::Pong(bytes) => {
+                    return Poll::Ready(Some(Ok(AggregatedMessage::Pong(bytes))))
+                }
+                Message::Close(reason) => {
+                    return Poll::Ready(Some(Ok(AggregatedMessage::Close(reason))))
+                }
+                Message::Continuation(Item::FirstText(bytes)) => {
+                    this.start(ContinuationKind::Text, bytes)
+                }
+                Message::Continuation(Item::FirstBinary(bytes)) => {
+                    this.start(ContinuationKind::Binary, bytes)
+                }
+                Message::Continuation(Item::Continue(bytes)) => this.append(bytes),
+                Message::Continuation(Item::Last(bytes)) => match this.append(bytes) {
+                    Some(early) => Some(early),
+                    None => return this.finish(),
+                },
+                Message::Nop => None,
+            };
+            if let Some(early) = early {
+                return early;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::ws::{CloseCode, CloseReason, Message, OpCode, ProtocolError};
+    use actix_web::web::Bytes;
+
+    use super::AggregatedMessage;
+    use crate::testing::connect;
+
+    fn close_code(messages: Vec<Message>) -> Option<CloseCode> {
+        match messages.as_slice() {
+            [Message::Close(Some(CloseReason { code, .. }))] => Some(*code),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragmented_messages() {
+        let (client, stream) = connect(false);
+        let mut stream = stream.aggregate_continuations();
+
+        client.send(OpCode::Text, b"Hello, ", false);
+        client.send(OpCode::Continue, b"wor", false);
+        client.send(OpCode::Continue, b"ld", true);
+        client.send(OpCode::Binary, b"\x00\x01", false);
+        client.send(OpCode::Continue, b"\x02", true);
+        client.send(OpCode::Text, b"whole", true);
+
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Text("Hello, world".into()),
+        );
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Binary(Bytes::from_static(b"\x00\x01\x02")),
+        );
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Text("whole".into()),
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_control_frames_within_fragmented_message() {
+        let (client, stream) = connect(false);
+        let mut stream = stream.aggregate_continuations();
+
+        client.send(OpCode::Binary, b"ab", false);
+        client.send(OpCode::Ping, b"p", true);
+        client.send(OpCode::Continue, b"cd", false);
+        client.send(OpCode::Pong, b"q", true);
+        client.send(OpCode::Continue, b"ef", true);
+
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Ping(Bytes::from_static(b"p")),
+        );
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Pong(Bytes::from_static(b"q")),
+        );
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Binary(Bytes::from_static(b"abcdef")),
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_with_size_on_overflow() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.aggregate_continuations().max_message_size(4);
+
+        client.send(OpCode::Text, b"abc", false);
+        client.send(OpCode::Continue, b"de", true);
+
+        assert!(matches!(
+            stream.recv().await,
+            Some(Err(ProtocolError::Overflow))
+        ));
+        assert_eq!(close_code(client.received()), Some(CloseCode::Size));
+    }
+
+    #[tokio::test]
+    async fn closes_with_size_on_large_single_frame() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.aggregate_continuations().max_message_size(4);
+
+        client.send(OpCode::Binary, b"abcde", true);
+
+        assert!(matches!(
+            stream.recv().await,
+            Some(Err(ProtocolError::Overflow))
+        ));
+        assert_eq!(close_code(client.received()), Some(CloseCode::Size));
+    }
+
+    #[tokio::test]
+    async fn rejects_continuation_without_opening_frame() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.aggregate_continuations();
+
+        client.send(OpCode::Continue, b"orphan", true);
+
+        assert!(matches!(
+            stream.recv().await,
+            Some(Err(ProtocolError::ContinuationNotStarted))
+        ));
+        assert_eq!(close_code(client.received()), Some(CloseCode::Protocol));
+    }
+
+    #[tokio::test]
+    async fn rejects_data_frame_within_fragmented_message() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.aggregate_continuations();
+
+        client.send(OpCode::Text, b"start", false);
+        client.send(OpCode::Text, b"interleaved", true);
+
+        assert!(matches!(
+            stream.recv().await,
+            Some(Err(ProtocolError::ContinuationStarted))
+        ));
+        assert_eq!(close_code(client.received()), Some(CloseCode::Protocol));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_utf8_across_fragments() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.aggregate_continuations();
+
+        // A two-byte sequence split between frames is fine, a truncated one is not
+        client.send(OpCode::Text, b"\xc3", false);
+        client.send(OpCode::Continue, b"\xa9", true);
+        client.send(OpCode::Text, b"\xc3", false);
+        client.send(OpCode::Continue, b"", true);
+
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Text("\u{e9}".into()),
+        );
+        assert!(matches!(
+            stream.recv().await,
+            Some(Err(ProtocolError::Io(_)))
+        ));
+        assert_eq!(close_code(client.received()), Some(CloseCode::Invalid));
+    }
+
+    #[tokio::test]
+    async fn ends_when_client_disconnects() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.aggregate_continuations();
+
+        client.send(OpCode::Text, b"last", true);
+        client.disconnect();
+
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Text("last".into()),
+        );
+        assert!(stream.recv().await.is_none());
+    }
+}
@@ -0,0 +1,316 @@
+//! The permessage-deflate extension from RFC 7692
+//!
+//! Contexts are never taken over between messages, in either direction, so that idle connections
+//! don't need to keep a sliding window around for what came before.
+
+use actix_http::{
+    header::{HeaderMap, SEC_WEBSOCKET_EXTENSIONS},
+    ws::{OpCode, Parser, ProtocolError},
+};
+use actix_web::web::BytesMut;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::io;
+
+const EXTENSION: &str = "permessage-deflate";
+
+/// Every flushed deflate stream ends with these bytes, which are left out on the wire
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The bit in a frame's first byte that marks a compressed message
+pub(crate) const RSV1: u8 = 0x40;
+
+/// Pick the first permessage-deflate offer in the request that can be accepted, returning the
+/// value of the `Sec-WebSocket-Extensions` response header that accepts it
+pub(crate) fn negotiate(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(SEC_WEBSOCKET_EXTENSIONS)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(accept)
+}
+
+fn accept(offer: &str) -> Option<String> {
+    let mut params = offer.split(';').map(str::trim);
+    if params.next() != Some(EXTENSION) {
+        return None;
+    }
+
+    let mut response = format!(
+        "{}; server_no_context_takeover; client_no_context_takeover",
+        EXTENSION
+    );
+    let mut seen = Vec::new();
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        if seen.contains(&name) {
+            return None;
+        }
+        seen.push(name);
+
+        match (name, value) {
+            ("server_no_context_takeover", None) | ("client_no_context_takeover", None) => (),
+            // Messages can only be compressed with the full window
+            ("server_max_window_bits", Some("15")) => {
+                response.push_str("; server_max_window_bits=15")
+            }
+            // The full window is always used for decompression, so any limit will do
+            ("client_max_window_bits", None) => (),
+            ("client_max_window_bits", Some(bits)) if matches!(bits.parse(), Ok(8..=15)) => (),
+            _ => return None,
+        }
+    }
+    Some(response)
+}
+
+/// Compresses outgoing messages
+pub(crate) struct Deflater {
+    compress: Compress,
+}
+
+impl Deflater {
+    pub(crate) fn new() -> Self {
+        Deflater {
+            compress: Compress::new(Compression::default(), false),
+        }
+    }
+
+    /// Compress a whole message and write it as a single frame
+    pub(crate) fn write_message(
+        &mut self,
+        dst: &mut BytesMut,
+        payload: &[u8],
+        op: OpCode,
+    ) -> Result<(), ProtocolError> {
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            out.reserve(4096);
+            let read = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&payload[read..], &mut out, FlushCompress::Sync)
+                .map_err(|e| ProtocolError::Io(io::Error::other(e)))?;
+            let read = (self.compress.total_in() - start) as usize;
+            if read == payload.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        self.compress.reset();
+
+        if out.ends_with(&TRAILER) {
+            out.truncate(out.len() - TRAILER.len());
+        }
+        let header = dst.len();
+        Parser::write_message(dst, out, op, true, false);
+        dst[header] |= RSV1;
+        Ok(())
+    }
+}
+
+/// Decompresses incoming messages, one frame at a time
+pub(crate) struct Inflater {
+    decompress: Decompress,
+}
+
+impl Inflater {
+    pub(crate) fn new() -> Self {
+        Inflater {
+            decompress: Decompress::new(false),
+        }
+    }
+
+    /// Decompress the payload of one frame of a compressed message
+    ///
+    /// Fails with [`ProtocolError::Overflow`] if it inflates to more than `max_size` bytes.
+    pub(crate) fn inflate(
+        &mut self,
+        payload: &[u8],
+        last: bool,
+        max_size: usize,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let mut out = Vec::with_capacity(payload.len() * 2);
+        self.inflate_into(payload, &mut out, max_size)?;
+        if last {
+            self.inflate_into(&TRAILER, &mut out, max_size)?;
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+
+    fn inflate_into(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+        max_size: usize,
+    ) -> Result<(), ProtocolError> {
+        let start = self.decompress.total_in();
+        loop {
+            out.reserve(4096);
+            let read = (self.decompress.total_in() - start) as usize;
+            let written = out.len();
+            let status = self
+                .decompress
+                .decompress_vec(&payload[read..], out, FlushDecompress::Sync)
+                .map_err(|e| ProtocolError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+            if out.len() > max_size {
+                return Err(ProtocolError::Overflow);
+            }
+
+            let progress =
+                (self.decompress.total_in() - start) as usize != read || out.len() != written;
+            let done = (self.decompress.total_in() - start) as usize == payload.len()
+                && out.len() < out.capacity();
+            // A final block ends the stream early, anything after it is ignored
+            if matches!(status, Status::StreamEnd) || done || !progress {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::{
+        header::{HeaderMap, HeaderValue, SEC_WEBSOCKET_EXTENSIONS},
+        ws::{OpCode, Parser, ProtocolError},
+    };
+    use actix_web::web::BytesMut;
+
+    use super::{negotiate, Deflater, Inflater, RSV1};
+
+    fn offer(values: &[&'static str]) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(SEC_WEBSOCKET_EXTENSIONS, HeaderValue::from_static(value));
+        }
+        negotiate(&headers)
+    }
+
+    #[test]
+    fn accepts_offers() {
+        let plain = "permessage-deflate; server_no_context_takeover; client_no_context_takeover";
+        let max_window = format!("{}; server_max_window_bits=15", plain);
+
+        assert_eq!(offer(&["permessage-deflate"]).as_deref(), Some(plain));
+        assert_eq!(
+            offer(&["permessage-deflate; client_max_window_bits"]).as_deref(),
+            Some(plain),
+        );
+        assert_eq!(
+            offer(&[
+                "permessage-deflate; client_max_window_bits=\"10\"; server_no_context_takeover"
+            ])
+            .as_deref(),
+            Some(plain),
+        );
+        assert_eq!(
+            offer(&["permessage-deflate;server_max_window_bits=15"]).as_deref(),
+            Some(&*max_window),
+        );
+        // The first offer that can be accepted wins, across and within headers
+        assert_eq!(
+            offer(&[
+                "x-webkit-deflate-frame",
+                "permessage-deflate; server_max_window_bits=10, permessage-deflate",
+            ])
+            .as_deref(),
+            Some(plain),
+        );
+    }
+
+    #[test]
+    fn rejects_offers() {
+        assert_eq!(offer(&[]), None);
+        assert_eq!(offer(&["x-webkit-deflate-frame"]), None);
+        assert_eq!(
+            offer(&["permessage-deflate; server_max_window_bits=10"]),
+            None
+        );
+        assert_eq!(offer(&["permessage-deflate; server_max_window_bits"]), None);
+        assert_eq!(
+            offer(&["permessage-deflate; client_max_window_bits=7"]),
+            None
+        );
+        assert_eq!(
+            offer(&["permessage-deflate; client_max_window_bits=x"]),
+            None
+        );
+        assert_eq!(
+            offer(&["permessage-deflate; server_no_context_takeover=1"]),
+            None
+        );
+        assert_eq!(
+            offer(&["permessage-deflate; client_no_context_takeover; client_no_context_takeover"]),
+            None,
+        );
+        assert_eq!(offer(&["permessage-deflate; unknown"]), None);
+    }
+
+    /// Take the payload out of a frame written by the [`Deflater`]
+    fn payload(frame: &mut BytesMut) -> BytesMut {
+        assert_ne!(frame[0] & RSV1, 0);
+        frame[0] &= !RSV1;
+        let (fin, _, payload) = Parser::parse(frame, false, usize::MAX).unwrap().unwrap();
+        assert!(fin);
+        payload.unwrap_or_default()
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let mut deflater = Deflater::new();
+        let mut inflater = Inflater::new();
+        let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let messages: [&[u8]; 4] = [b"hello", b"", &big, b"hello"];
+
+        for message in messages {
+            let mut frame = BytesMut::new();
+            deflater
+                .write_message(&mut frame, message, OpCode::Binary)
+                .unwrap();
+            let payload = payload(&mut frame);
+            assert!(frame.is_empty());
+            assert_eq!(
+                inflater.inflate(&payload, true, usize::MAX).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn inflates_across_frames() {
+        let mut frame = BytesMut::new();
+        Deflater::new()
+            .write_message(&mut frame, b"split into several frames", OpCode::Text)
+            .unwrap();
+        let payload = payload(&mut frame);
+        let (first, rest) = payload.split_at(payload.len() / 2);
+
+        let mut inflater = Inflater::new();
+        let mut out = inflater.inflate(first, false, usize::MAX).unwrap();
+        out.extend(inflater.inflate(rest, true, usize::MAX).unwrap());
+        assert_eq!(out, b"split into several frames");
+    }
+
+    #[test]
+    fn limits_inflated_size() {
+        let mut frame = BytesMut::new();
+        Deflater::new()
+            .write_message(&mut frame, &[0; 10_000], OpCode::Binary)
+            .unwrap();
+        let payload = payload(&mut frame);
+
+        assert!(matches!(
+            Inflater::new().inflate(&payload, true, 9_999),
+            Err(ProtocolError::Overflow)
+        ));
+        assert_eq!(
+            Inflater::new()
+                .inflate(&payload, true, 10_000)
+                .unwrap()
+                .len(),
+            10_000
+        );
+    }
+}
@@ -1,25 +1,34 @@
+use crate::deflate::{Deflater, Inflater, RSV1};
 use actix_codec::{Decoder, Encoder};
 use actix_http::{
-    ws::{Codec, Frame, Message, ProtocolError},
+    ws::{CloseCode, CloseReason, Codec, Frame, Item, Message, OpCode, ProtocolError},
     Payload,
 };
 use actix_web::{
     web::{Bytes, BytesMut},
     Error,
 };
+use bytestring::ByteString;
 use futures_core::stream::Stream;
 use std::{
     collections::VecDeque,
+    convert::TryFrom,
     future::Future,
     io,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    time::{Instant, Interval, MissedTickBehavior},
 };
-use tokio::sync::mpsc::Receiver;
 
 /// A response body for Websocket HTTP Requests
 pub struct StreamingBody {
     session_rx: Receiver<Message>,
+    deflater: Option<Deflater>,
 
     messages: VecDeque<Message>,
     buf: BytesMut,
@@ -32,17 +41,24 @@ pub struct StreamingBody {
 /// Messages can be accessed via the stream's `.next()` method
 pub struct MessageStream {
     payload: Payload,
+    session_tx: Sender<Message>,
+    heartbeat: Option<Heartbeat>,
+    inflater: Option<Inflater>,
+    // Whether the fragmented message being received is compressed
+    inflating: bool,
 
     messages: VecDeque<Message>,
     buf: BytesMut,
     codec: Codec,
+    max_size: usize,
     closing: bool,
 }
 
 impl StreamingBody {
-    pub(super) fn new(session_rx: Receiver<Message>) -> Self {
+    pub(super) fn new(session_rx: Receiver<Message>, deflate: bool) -> Self {
         StreamingBody {
             session_rx,
+            deflater: if deflate { Some(Deflater::new()) } else { None },
             messages: VecDeque::new(),
             buf: BytesMut::new(),
             codec: Codec::new().max_size(2 << 20),
@@ -52,16 +68,109 @@ impl StreamingBody {
 }
 
 impl MessageStream {
-    pub(super) fn new(payload: Payload) -> Self {
+    pub(super) fn new(payload: Payload, session_tx: Sender<Message>, deflate: bool) -> Self {
         MessageStream {
             payload,
+            session_tx,
+            heartbeat: None,
+            inflater: if deflate { Some(Inflater::new()) } else { None },
+            inflating: false,
             messages: VecDeque::new(),
             buf: BytesMut::new(),
             codec: Codec::new().max_size(2 << 20),
+            max_size: 2 << 20,
             closing: false,
         }
     }
 
+    /// Set the maximum size of a single frame, 2 MiB by default
+    ///
+    /// Larger frames fail with [`ProtocolError::Overflow`] and close the connection. Compressed
+    /// frames are held to this size both before and after decompression.
+    pub fn max_frame_size(mut self, max_size: usize) -> Self {
+        self.codec = self.codec.max_size(max_size);
+        self.max_size = max_size;
+        self
+    }
+
+    /// Ping the client every `interval`, and end the stream once nothing has been heard from it
+    /// for `timeout`
+    ///
+    /// Pings from the client are answered automatically while a heartbeat is set, but are still
+    /// yielded from the stream. Must be called from within the actix runtime.
+    ///
+    /// ```rust,no_run
+    /// # use actix_ws::MessageStream;
+    /// # use std::time::Duration;
+    /// # fn handle(msg_stream: MessageStream) {
+    /// let msg_stream = msg_stream.heartbeat(Duration::from_secs(5), Duration::from_secs(30));
+    /// # }
+    /// ```
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.heartbeat = Some(Heartbeat {
+            ticks,
+            timeout,
+            last_seen: Instant::now(),
+        });
+        self
+    }
+
+    /// Decompress a data frame if it belongs to a compressed message
+    fn inflate(&mut self, frame: Frame, compressed: bool) -> Result<Frame, ProtocolError> {
+        let inflater = match &mut self.inflater {
+            Some(inflater) => inflater,
+            None => return Ok(frame),
+        };
+        let max_size = self.max_size;
+        let mut inflate =
+            |bytes: Bytes, last| inflater.inflate(&bytes, last, max_size).map(Bytes::from);
+
+        let res = match frame {
+            Frame::Text(bytes) if compressed => inflate(bytes, true).map(Frame::Text),
+            Frame::Binary(bytes) if compressed => inflate(bytes, true).map(Frame::Binary),
+            Frame::Continuation(item) => {
+                // Only the first frame of a message is marked as compressed
+                if let Item::FirstText(_) | Item::FirstBinary(_) = item {
+                    self.inflating = compressed;
+                }
+                if !self.inflating {
+                    return Ok(Frame::Continuation(item));
+                }
+                match item {
+                    Item::FirstText(bytes) => inflate(bytes, false).map(Item::FirstText),
+                    Item::FirstBinary(bytes) => inflate(bytes, false).map(Item::FirstBinary),
+                    Item::Continue(bytes) => inflate(bytes, false).map(Item::Continue),
+                    Item::Last(bytes) => {
+                        self.inflating = false;
+                        inflate(bytes, true).map(Item::Last)
+                    }
+                }
+                .map(Frame::Continuation)
+            }
+            frame => return Ok(frame),
+        };
+        res.map_err(|e| {
+            match e {
+                ProtocolError::Overflow => self.close_with(CloseCode::Size, "frame too large"),
+                _ => self.close_with(CloseCode::Invalid, "invalid compressed data"),
+            }
+            e
+        })
+    }
+
+    /// Close the connection because the client misbehaved
+    ///
+    /// The close frame is dropped if the session's queue is full, since the client is not
+    /// listening anyway in that case.
+    pub(super) fn close_with(&self, code: CloseCode, description: &str) {
+        let _ = self.session_tx.try_send(Message::Close(Some(CloseReason {
+            code,
+            description: Some(description.to_owned()),
+        })));
+    }
+
     /// Wait for the next item from the message stream
     ///
     /// ```rust,ignore
@@ -74,11 +183,30 @@ impl MessageStream {
     }
 }
 
-struct PollFn<F>(F);
+struct Heartbeat {
+    ticks: Interval,
+    timeout: Duration,
+    last_seen: Instant,
+}
+
+impl Heartbeat {
+    /// Returns whether the client went quiet for too long
+    fn poll_expired(&mut self, session_tx: &Sender<Message>, cx: &mut Context<'_>) -> bool {
+        while self.ticks.poll_tick(cx).is_ready() {
+            if self.last_seen.elapsed() >= self.timeout {
+                return true;
+            }
+            let _ = session_tx.try_send(Message::Ping(Bytes::new()));
+        }
+        false
+    }
+}
+
+pub(super) struct PollFn<F>(F);
 
 impl<F> Unpin for PollFn<F> {}
 
-fn poll_fn<F, T>(f: F) -> PollFn<F>
+pub(super) fn poll_fn<F, T>(f: F) -> PollFn<F>
 where
     F: FnMut(&mut Context<'_>) -> Poll<T>,
 {
@@ -92,7 +220,7 @@ where
     type Output = T;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        (&mut self.0)(cx)
+        (self.0)(cx)
     }
 }
 
@@ -100,7 +228,7 @@ impl Stream for StreamingBody {
     type Item = Result<Bytes, Error>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut this = self.get_mut();
+        let this = self.get_mut();
 
         if this.closing {
             return Poll::Ready(None);
@@ -108,6 +236,12 @@ impl Stream for StreamingBody {
 
         loop {
             match Pin::new(&mut this.session_rx).poll_recv(cx) {
+                // Nothing may be sent after a close frame
+                Poll::Ready(Some(msg @ Message::Close(_))) => {
+                    this.messages.push_back(msg);
+                    this.closing = true;
+                    break;
+                }
                 Poll::Ready(Some(msg)) => {
                     this.messages.push_back(msg);
                 }
@@ -120,7 +254,16 @@ impl Stream for StreamingBody {
         }
 
         while let Some(msg) = this.messages.pop_front() {
-            if let Err(e) = this.codec.encode(msg, &mut this.buf) {
+            let res = match (&mut this.deflater, msg) {
+                (Some(deflater), Message::Text(text)) => {
+                    deflater.write_message(&mut this.buf, text.as_bytes(), OpCode::Text)
+                }
+                (Some(deflater), Message::Binary(bin)) => {
+                    deflater.write_message(&mut this.buf, &bin, OpCode::Binary)
+                }
+                (_, msg) => this.codec.encode(msg, &mut this.buf),
+            };
+            if let Err(e) = res {
                 return Poll::Ready(Some(Err(e.into())));
             }
         }
@@ -137,7 +280,7 @@ impl Stream for MessageStream {
     type Item = Result<Message, ProtocolError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut this = self.get_mut();
+        let this = self.get_mut();
 
         // Return the first message in the queue if one exists
         //
@@ -151,11 +294,13 @@ impl Stream for MessageStream {
             loop {
                 match Pin::new(&mut this.payload).poll_next(cx) {
                     Poll::Ready(Some(Ok(bytes))) => {
+                        if let Some(heartbeat) = &mut this.heartbeat {
+                            heartbeat.last_seen = Instant::now();
+                        }
                         this.buf.extend_from_slice(&bytes);
                     }
                     Poll::Ready(Some(Err(e))) => {
-                        return Poll::Ready(Some(Err(ProtocolError::Io(io::Error::new(
-                            io::ErrorKind::Other,
+                        return Poll::Ready(Some(Err(ProtocolError::Io(io::Error::other(
                             e.to_string(),
                         )))));
                     }
@@ -169,18 +314,63 @@ impl Stream for MessageStream {
         }
 
         // Create messages until there's no more bytes left
-        while let Some(frame) = this.codec.decode(&mut this.buf)? {
-            let message = match frame {
-                Frame::Text(bytes) => {
-                    let s = std::str::from_utf8(&bytes)
-                        .map_err(|e| {
-                            ProtocolError::Io(io::Error::new(io::ErrorKind::Other, e.to_string()))
-                        })?
-                        .to_string();
-                    Message::Text(s.into())
+        loop {
+            // The codec doesn't know about extensions, so take RSV1 out of the header before it
+            // gets to see it
+            let compressed = this.buf.first().is_some_and(|b| b & RSV1 != 0);
+            if compressed {
+                // Only the first frame of a data message may be compressed, and only once
+                // permessage-deflate has been negotiated
+                let opcode = this.buf[0] & 0x0F;
+                if this.inflater.is_none() || opcode == 0 || opcode >= 8 {
+                    this.close_with(CloseCode::Protocol, "unexpected RSV1");
+                    return Poll::Ready(Some(Err(ProtocolError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected RSV1",
+                    )))));
                 }
+                this.buf[0] &= !RSV1;
+            }
+            let frame = match this.codec.decode(&mut this.buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    // Keep the header as it was until the rest of the frame arrives
+                    if compressed {
+                        this.buf[0] |= RSV1;
+                    }
+                    break;
+                }
+                Err(e) => {
+                    match e {
+                        ProtocolError::Overflow => {
+                            this.close_with(CloseCode::Size, "frame too large")
+                        }
+                        _ => this.close_with(CloseCode::Protocol, "protocol error"),
+                    }
+                    return Poll::Ready(Some(Err(e)));
+                }
+            };
+            let frame = match this.inflate(frame, compressed) {
+                Ok(frame) => frame,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            };
+            let message = match frame {
+                Frame::Text(bytes) => match ByteString::try_from(bytes) {
+                    Ok(s) => Message::Text(s),
+                    Err(e) => {
+                        this.close_with(CloseCode::Invalid, "invalid UTF-8");
+                        return Poll::Ready(Some(Err(ProtocolError::Io(io::Error::other(
+                            e.to_string(),
+                        )))));
+                    }
+                },
                 Frame::Binary(bytes) => Message::Binary(bytes),
-                Frame::Ping(bytes) => Message::Ping(bytes),
+                Frame::Ping(bytes) => {
+                    if this.heartbeat.is_some() {
+                        let _ = this.session_tx.try_send(Message::Pong(bytes.clone()));
+                    }
+                    Message::Ping(bytes)
+                }
                 Frame::Pong(bytes) => Message::Pong(bytes),
                 Frame::Close(reason) => Message::Close(reason),
                 Frame::Continuation(item) => Message::Continuation(item),
@@ -194,6 +384,17 @@ impl Stream for MessageStream {
             return Poll::Ready(Some(Ok(msg)));
         }
 
+        // Only check for a timeout once everything the client sent has been read, since the
+        // consumer may have been too busy to poll for a while
+        if let Some(heartbeat) = &mut this.heartbeat {
+            if !this.closing && heartbeat.poll_expired(&this.session_tx, cx) {
+                this.close_with(CloseCode::Away, "heartbeat timeout");
+                this.closing = true;
+                this.buf.clear();
+                return Poll::Ready(None);
+            }
+        }
+
         // If we've exhausted our message queue and we're closing, close the stream
         if this.closing {
             return Poll::Ready(None);
@@ -202,3 +403,208 @@ impl Stream for MessageStream {
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_http::ws::{CloseCode, CloseReason, Item, Message, OpCode, ProtocolError};
+    use actix_web::web::Bytes;
+    use tokio::time::{sleep, timeout, Instant};
+
+    use crate::{
+        testing::{compress, connect, frame},
+        AggregatedMessage,
+    };
+
+    fn close_code(messages: Vec<Message>) -> Option<CloseCode> {
+        match messages.as_slice() {
+            [Message::Close(Some(CloseReason { code, .. }))] => Some(*code),
+            _ => None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pings_at_interval() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.heartbeat(Duration::from_secs(5), Duration::from_secs(30));
+
+        assert!(timeout(Duration::from_secs(12), stream.recv())
+            .await
+            .is_err());
+        assert_eq!(
+            client.received(),
+            vec![Message::Ping(Bytes::new()), Message::Ping(Bytes::new())],
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answers_pings() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.heartbeat(Duration::from_secs(5), Duration::from_secs(30));
+
+        client.send(OpCode::Ping, b"hi", true);
+
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            Message::Ping(Bytes::from_static(b"hi")),
+        );
+        assert_eq!(
+            client.received(),
+            vec![Message::Pong(Bytes::from_static(b"hi"))],
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaves_pings_without_heartbeat() {
+        let (mut client, mut stream) = connect(false);
+
+        client.send(OpCode::Ping, b"hi", true);
+
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            Message::Ping(Bytes::from_static(b"hi")),
+        );
+        assert!(client.received().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_idle_connection() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.heartbeat(Duration::from_secs(5), Duration::from_secs(12));
+        let start = Instant::now();
+
+        assert!(stream.recv().await.is_none());
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+        assert_eq!(
+            client.received(),
+            vec![
+                Message::Ping(Bytes::new()),
+                Message::Ping(Bytes::new()),
+                Message::Close(Some(CloseReason {
+                    code: CloseCode::Away,
+                    description: Some("heartbeat timeout".to_owned()),
+                })),
+            ],
+        );
+        assert!(stream.recv().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_talking_connection_open() {
+        let (mut client, stream) = connect(false);
+        let mut stream = stream.heartbeat(Duration::from_secs(5), Duration::from_secs(12));
+
+        // The stream isn't polled while sleeping, so the timeout has to wait for what's been sent
+        for _ in 0..6 {
+            client.send(OpCode::Pong, b"", true);
+            assert_eq!(
+                stream.recv().await.unwrap().unwrap(),
+                Message::Pong(Bytes::new())
+            );
+            sleep(Duration::from_secs(4)).await;
+        }
+
+        assert!(!client
+            .received()
+            .iter()
+            .any(|msg| matches!(msg, Message::Close(_))));
+    }
+
+    #[tokio::test]
+    async fn inflates_compressed_messages() {
+        let (client, stream) = connect(true);
+        let mut stream = stream.aggregate_continuations();
+
+        client.send_rsv1(OpCode::Text, &compress(b"compressed"), true);
+        client.send(OpCode::Text, b"plain", true);
+        let payload = compress(b"compressed in pieces");
+        let (first, rest) = payload.split_at(payload.len() / 2);
+        client.send_rsv1(OpCode::Binary, first, false);
+        client.send(OpCode::Ping, b"", true);
+        client.send(OpCode::Continue, rest, true);
+
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Text("compressed".into()),
+        );
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Text("plain".into()),
+        );
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Ping(Bytes::new()),
+        );
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            AggregatedMessage::Binary(Bytes::from_static(b"compressed in pieces")),
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_for_rest_of_compressed_frame() {
+        let (client, mut stream) = connect(true);
+
+        let mut bytes = frame(OpCode::Text, &compress(b"slow"), true, true);
+        let rest = bytes.split_off(1);
+        client.send_raw(bytes);
+        assert!(timeout(Duration::from_millis(10), stream.recv())
+            .await
+            .is_err());
+        client.send_raw(rest);
+
+        assert_eq!(
+            stream.recv().await.unwrap().unwrap(),
+            Message::Text("slow".into()),
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_rsv1_without_deflate() {
+        let (mut client, mut stream) = connect(false);
+
+        client.send_rsv1(OpCode::Text, &compress(b"compressed"), true);
+
+        assert!(matches!(
+            stream.recv().await,
+            Some(Err(ProtocolError::Io(_)))
+        ));
+        assert_eq!(close_code(client.received()), Some(CloseCode::Protocol));
+    }
+
+    #[tokio::test]
+    async fn rejects_rsv1_on_control_frames() {
+        for op in [OpCode::Ping, OpCode::Pong, OpCode::Close] {
+            let (mut client, mut stream) = connect(true);
+
+            client.send_rsv1(op, b"", true);
+
+            assert!(matches!(
+                stream.recv().await,
+                Some(Err(ProtocolError::Io(_)))
+            ));
+            assert_eq!(close_code(client.received()), Some(CloseCode::Protocol));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_rsv1_on_continuation() {
+        let (mut client, mut stream) = connect(true);
+        let payload = compress(b"compressed in pieces");
+        let (first, rest) = payload.split_at(payload.len() / 2);
+
+        client.send_rsv1(OpCode::Binary, first, false);
+        assert!(matches!(
+            stream.recv().await,
+            Some(Ok(Message::Continuation(Item::FirstBinary(_))))
+        ));
+        client.send_rsv1(OpCode::Continue, rest, true);
+
+        assert!(matches!(
+            stream.recv().await,
+            Some(Err(ProtocolError::Io(_)))
+        ));
+        assert_eq!(close_code(client.received()), Some(CloseCode::Protocol));
+    }
+}
@@ -6,6 +6,7 @@
 
 use actix_http::{
     body::{BodyStream, MessageBody},
+    header::SEC_WEBSOCKET_EXTENSIONS,
     ws::handshake,
 };
 use actix_web::{web, HttpRequest, HttpResponse};
@@ -13,10 +14,15 @@ use tokio::sync::mpsc::channel;
 
 pub use actix_http::ws::{CloseCode, CloseReason, Item, Message, ProtocolError};
 
+mod aggregated;
+mod deflate;
 mod fut;
 mod session;
+#[cfg(test)]
+mod testing;
 
 pub use self::{
+    aggregated::{AggregatedMessage, AggregatedMessageStream},
     fut::{MessageStream, StreamingBody},
     session::{Closed, Session},
 };
@@ -67,14 +73,61 @@ pub fn handle(
     req: &HttpRequest,
     body: web::Payload,
 ) -> Result<(HttpResponse, Session, MessageStream), actix_web::Error> {
-    let mut response = handshake(req.head())?;
-    let (tx, rx) = channel(32);
+    Config::new().handle(req, body)
+}
+
+/// Options for accepting websocket connections
+///
+/// ```rust,ignore
+/// let (response, session, msg_stream) = actix_ws::Config::new()
+///     .deflate(true)
+///     .handle(&req, body)?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    deflate: bool,
+}
+
+impl Config {
+    /// Options to accept connections just like [`handle`] does
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compress messages with permessage-deflate if the client offers it, off by default
+    ///
+    /// Worth it for large text messages, but a waste of CPU for data that is already compressed.
+    pub fn deflate(mut self, deflate: bool) -> Self {
+        self.deflate = deflate;
+        self
+    }
+
+    /// Begin handling websocket traffic with these options
+    ///
+    /// See [`handle`] for usage
+    pub fn handle(
+        &self,
+        req: &HttpRequest,
+        body: web::Payload,
+    ) -> Result<(HttpResponse, Session, MessageStream), actix_web::Error> {
+        let mut response = handshake(req.head())?;
+        let extensions = if self.deflate {
+            deflate::negotiate(req.headers())
+        } else {
+            None
+        };
+        let deflate = extensions.is_some();
+        if let Some(extensions) = extensions {
+            response.insert_header((SEC_WEBSOCKET_EXTENSIONS, extensions));
+        }
+        let (tx, rx) = channel(32);
 
-    Ok((
-        response
-            .message_body(BodyStream::new(StreamingBody::new(rx)).boxed())?
-            .into(),
-        Session::new(tx),
-        MessageStream::new(body.into_inner()),
-    ))
+        Ok((
+            response
+                .message_body(BodyStream::new(StreamingBody::new(rx, deflate)).boxed())?
+                .into(),
+            Session::new(tx.clone()),
+            MessageStream::new(body.into_inner(), tx, deflate),
+        ))
+    }
 }
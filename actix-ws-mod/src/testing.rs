@@ -0,0 +1,104 @@
+//! A fake client that feeds a [`MessageStream`] with frames, for unit tests
+
+use actix_http::{
+    error::PayloadError,
+    ws::{Message, OpCode, Parser},
+    BoxedPayloadStream, Payload,
+};
+use actix_web::web::{Bytes, BytesMut};
+use futures_core::stream::Stream;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc::{channel, unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    deflate::{Deflater, RSV1},
+    MessageStream,
+};
+
+struct ChannelPayload(UnboundedReceiver<Bytes>);
+
+impl Stream for ChannelPayload {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx).map(|bytes| bytes.map(Ok))
+    }
+}
+
+pub(crate) struct Client {
+    tx: Option<UnboundedSender<Bytes>>,
+    session_rx: Receiver<Message>,
+}
+
+/// Open a connection, returning the client's end and the server's stream of messages
+pub(crate) fn connect(deflate: bool) -> (Client, MessageStream) {
+    let (tx, rx) = unbounded_channel();
+    let (session_tx, session_rx) = channel(32);
+    let payload: BoxedPayloadStream = Box::pin(ChannelPayload(rx));
+    let client = Client {
+        tx: Some(tx),
+        session_rx,
+    };
+    (
+        client,
+        MessageStream::new(Payload::from(payload), session_tx, deflate),
+    )
+}
+
+impl Client {
+    /// Send one masked frame
+    pub(crate) fn send(&self, op: OpCode, payload: &[u8], fin: bool) {
+        self.send_raw(frame(op, payload, fin, false));
+    }
+
+    /// Send one masked frame with RSV1 set, which marks a compressed message
+    pub(crate) fn send_rsv1(&self, op: OpCode, payload: &[u8], fin: bool) {
+        self.send_raw(frame(op, payload, fin, true));
+    }
+
+    /// Send bytes as they are, which needn't be a whole frame
+    pub(crate) fn send_raw(&self, bytes: BytesMut) {
+        let tx = self.tx.as_ref().expect("connection closed");
+        tx.send(bytes.freeze()).expect("stream dropped");
+    }
+
+    /// End the connection from the client's side
+    pub(crate) fn disconnect(&mut self) {
+        self.tx = None;
+    }
+
+    /// Everything the server has queued for the client so far
+    pub(crate) fn received(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while let Ok(msg) = self.session_rx.try_recv() {
+            messages.push(msg);
+        }
+        messages
+    }
+}
+
+pub(crate) fn frame(op: OpCode, payload: &[u8], fin: bool, rsv1: bool) -> BytesMut {
+    let mut bytes = BytesMut::new();
+    Parser::write_message(&mut bytes, payload, op, fin, true);
+    if rsv1 {
+        bytes[0] |= RSV1;
+    }
+    bytes
+}
+
+/// Compress a message the way the server would, returning the payload of its frame
+pub(crate) fn compress(payload: &[u8]) -> BytesMut {
+    let mut bytes = BytesMut::new();
+    Deflater::new()
+        .write_message(&mut bytes, payload, OpCode::Binary)
+        .unwrap();
+    assert_ne!(bytes[0] & RSV1, 0);
+    bytes[0] &= !RSV1;
+    let (_, _, payload) = Parser::parse(&mut bytes, false, usize::MAX)
+        .unwrap()
+        .unwrap();
+    payload.unwrap_or_default()
+}
@@ -2,87 +2,114 @@ import { TinyEmitter } from 'tiny-emitter';
 
 export class FileUploader {
   private readonly BLOCK_SIZE = (256 << 10);
+  private readonly MAX_CONNECTIONS = 4;
 
   private file: File;
   private uuid: string;
-  private socket: WebSocket | undefined;
-  // private fileReader = new FileReader();
+  private sockets: WebSocket[] = [];
   private eventEmitter = new TinyEmitter();
 
-  private current = 0;
+  // Offset of the next block to be sent, over whichever connection is free
+  private next = 0;
+  // Number of bytes the server has confirmed
+  private received = 0;
   private running = false;
+  private finishing = false;
   private error: string | undefined;
 
   constructor(file: File, uuid: string) {
     this.file = file;
     this.uuid = uuid;
-
-    // this.fileReader.onload = () => this.startNextBlock();
-    // this.fileReader.onerror = (ev) => {
-    //   console.error('Failed to read file:', ev);
-    //   this.error = 'failed to read file';
-    //   this.socket?.close();
-    //   this.eventEmitter.emit('progress');
-    // };
   }
 
   start(): void {
     this.running = true;
-    this.socket = new WebSocket(import.meta.env.VITE_WS_BASE_URL + '/api/uploads/' + this.uuid);
-    this.socket.onopen = () => {
-      this.startNextBlock();
+    const numBlocks = Math.ceil(this.file.size / this.BLOCK_SIZE);
+    const numConnections = Math.max(1, Math.min(this.MAX_CONNECTIONS, numBlocks));
+    for (let i = 0; i < numConnections; i++) {
+      this.connect();
+    }
+  }
+
+  private connect() {
+    const socket = new WebSocket(
+      import.meta.env.VITE_WS_BASE_URL + '/api/uploads/' + this.uuid + '?offsets=true');
+    this.sockets.push(socket);
+    socket.onopen = () => {
+      this.sendNextBlock(socket);
     };
-    this.socket.onmessage = (ev) => {
-      if (typeof ev.data === 'string') {
-        const resp = JSON.parse(ev.data);
-        if (this.running) {
-          if (resp.cur_pos !== this.current) {
-            console.warn('File position mismatch: local', this.current, 'remote', resp.cur_pos);
-          }
-          this.eventEmitter.emit('progress');
-          this.startNextBlock();
-        } else {
-          // Upload done
-          this.error = resp.err || undefined;
-          this.eventEmitter.emit('progress');
-          this.socket?.close();
-        }
-      } else {
+    socket.onmessage = (ev) => {
+      if (typeof ev.data !== 'string') {
         console.warn('Uploader socket received data of unknown type:', ev);
+        return;
+      }
+      const resp = JSON.parse(ev.data);
+      if (this.finishing) {
+        // Upload done
+        this.error = resp.err || undefined;
+        this.finishing = false;
+        this.eventEmitter.emit('progress');
+        this.closeAll();
+      } else if (this.running) {
+        if (resp.err) {
+          this.fail(resp.err);
+          return;
+        }
+        this.received += resp.len;
+        this.eventEmitter.emit('progress');
+        this.sendNextBlock(socket);
       }
     };
-    this.socket.onerror = (ev) => {
+    socket.onerror = (ev) => {
       console.error('Upload socket error:', ev);
-      this.socket?.close();
+      socket.close();
     };
-    this.socket.onclose = (ev) => {
-      if (!this.running) return;
+    socket.onclose = (ev) => {
+      if (!this.running && !this.finishing) return;
       console.error('Upload socket closed by server:', ev);
-      this.error = 'socket closed by server';
-      this.running = false;
-      this.eventEmitter.emit('progress');
+      this.fail('socket closed by server');
     };
   }
 
-  private startNextBlock() {
-    if (this.current === this.file.size) {
-      // Finished
+  private sendNextBlock(socket: WebSocket) {
+    if (this.next < this.file.size) {
+      // Each block starts with its offset as a 64-bit big-endian integer
+      const blockEnd = Math.min(this.next + this.BLOCK_SIZE, this.file.size);
+      const header = new DataView(new ArrayBuffer(8));
+      header.setUint32(0, Math.floor(this.next / 0x100000000));
+      header.setUint32(4, this.next % 0x100000000);
+      socket.send(new Blob([header, this.file.slice(this.next, blockEnd)]));
+      this.next = blockEnd;
+    } else if (this.received === this.file.size) {
+      // Every block has arrived, so one connection finishes the upload
       this.running = false;
-      this.socket?.send(JSON.stringify({
+      this.finishing = true;
+      socket.send(JSON.stringify({
         'cmd': 'Finish',
       }));
-    } else {
-      const block_end = Math.min(this.current + this.BLOCK_SIZE, this.file.size);
-      this.socket!.send(this.file.slice(this.current, block_end));
-      this.current = block_end;
     }
   }
 
+  private fail(err: string) {
+    this.error = err;
+    this.running = false;
+    this.finishing = false;
+    this.eventEmitter.emit('progress');
+    this.closeAll();
+  }
+
+  private closeAll() {
+    for (const socket of this.sockets) {
+      socket.close();
+    }
+    this.sockets = [];
+  }
+
   onProgress(callback: Function): void {
     this.eventEmitter.on('progress', callback);
   }
 
-  isRunning(): boolean { return this.running; }
-  getPercentage(): number { return this.current / this.file.size; }
+  isRunning(): boolean { return this.running || this.finishing; }
+  getPercentage(): number { return this.file.size === 0 ? 1 : this.received / this.file.size; }
   getError(): string | undefined { return this.error; }
 }
@@ -0,0 +1,5 @@
+ALTER TABLE users ADD COLUMN role VARCHAR NOT NULL DEFAULT 'user';
+ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT 0;
+
+-- The oldest account becomes the first administrator
+UPDATE users SET role = 'admin' WHERE id = (SELECT MIN(id) FROM users);
@@ -0,0 +1,7 @@
+CREATE TABLE invites (
+    code VARCHAR NOT NULL PRIMARY KEY,
+    created_by INTEGER NOT NULL REFERENCES users(id),
+    max_uses INTEGER NOT NULL,
+    num_uses INTEGER NOT NULL DEFAULT 0,
+    expires BIGINT NOT NULL
+);
@@ -0,0 +1,7 @@
+CREATE TABLE trash (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    web_path VARCHAR NOT NULL,
+    stored_name VARCHAR NOT NULL UNIQUE,
+    deleted_at BIGINT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP TABLE upload_links;
+DROP TABLE download_links;
@@ -0,0 +1,16 @@
+CREATE TABLE download_links (
+    uuid VARCHAR NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    file VARCHAR NOT NULL,
+    expires BIGINT NOT NULL
+);
+
+CREATE TABLE upload_links (
+    uuid VARCHAR NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    file VARCHAR NOT NULL,
+    size BIGINT NOT NULL,
+    started BOOLEAN NOT NULL DEFAULT 0,
+    finished BOOLEAN NOT NULL DEFAULT 0,
+    expires BIGINT NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE download_links DROP COLUMN num_downloads;
+ALTER TABLE download_links DROP COLUMN max_downloads;
+ALTER TABLE download_links DROP COLUMN hashed_pass;
@@ -0,0 +1,3 @@
+ALTER TABLE download_links ADD COLUMN hashed_pass VARCHAR;
+ALTER TABLE download_links ADD COLUMN max_downloads INTEGER;
+ALTER TABLE download_links ADD COLUMN num_downloads INTEGER NOT NULL DEFAULT 0;
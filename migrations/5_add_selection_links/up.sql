@@ -0,0 +1 @@
+ALTER TABLE download_links ADD COLUMN entries TEXT;
@@ -0,0 +1 @@
+ALTER TABLE upload_links DROP COLUMN checksum;
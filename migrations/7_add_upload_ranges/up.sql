@@ -0,0 +1 @@
+ALTER TABLE upload_links ADD COLUMN received TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,8 @@
+CREATE TABLE acl (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    owner_id INTEGER NOT NULL REFERENCES users(id),
+    path VARCHAR NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    permissions INTEGER NOT NULL,
+    UNIQUE (owner_id, path, user_id)
+);
@@ -0,0 +1,19 @@
+CREATE TABLE groups (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name VARCHAR NOT NULL UNIQUE
+);
+
+CREATE TABLE group_members (
+    group_id INTEGER NOT NULL REFERENCES groups(id),
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE TABLE group_acl (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    owner_id INTEGER NOT NULL REFERENCES users(id),
+    path VARCHAR NOT NULL,
+    group_id INTEGER NOT NULL REFERENCES groups(id),
+    permissions INTEGER NOT NULL,
+    UNIQUE (owner_id, path, group_id)
+);
@@ -0,0 +1,550 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection,
+};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    home::HOME_PREFIX,
+    models::{AclEntry, GroupAclEntry, NewAclEntry, NewGroupAclEntry},
+    safe_path::to_web_path,
+};
+
+/// What a user may do in a directory that somebody else owns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// List directories.
+    Read,
+    /// Upload files and create directories, without touching existing ones.
+    Upload,
+    /// Rename, move and delete existing entries.
+    Modify,
+    /// Create download and share links, which work without an account.
+    Share,
+}
+
+impl Permission {
+    const ALL: [Permission; 4] = [
+        Permission::Read,
+        Permission::Upload,
+        Permission::Modify,
+        Permission::Share,
+    ];
+
+    fn bit(self) -> i32 {
+        1 << self as i32
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Permission::Read => "read",
+            Permission::Upload => "upload",
+            Permission::Modify => "modify",
+            Permission::Share => "share",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A set of permissions, stored in the database as a bit mask.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Permissions(i32);
+
+impl Permissions {
+    pub fn from_list(list: &[Permission]) -> Permissions {
+        Permissions(
+            list.iter()
+                .fold(0, |mask, permission| mask | permission.bit()),
+        )
+    }
+
+    pub fn contains(self, permission: Permission) -> bool {
+        self.0 & permission.bit() != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Serialize for Permissions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            Permission::ALL
+                .iter()
+                .filter(|permission| self.contains(**permission)),
+        )
+    }
+}
+
+/// Whom access is granted to.
+#[derive(Clone, Copy, Debug)]
+pub enum Principal {
+    User(i32),
+    Group(i32),
+}
+
+/// Access that an owner has granted to somebody else.
+#[derive(Serialize)]
+pub struct Grant {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub permissions: Permissions,
+}
+
+/// A directory that somebody else has shared with the user.
+#[derive(Serialize)]
+pub struct SharedEntry {
+    /// Web path of the directory, starting with `~owner`.
+    pub path: String,
+    /// The group it was shared with, if not with the user directly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub permissions: Permissions,
+}
+
+fn owner_web_path(owner_id: i32, path: &str, db: &mut SqliteConnection) -> anyhow::Result<String> {
+    let owner = crate::user::find_username(owner_id, db)?;
+    Ok(if path.is_empty() {
+        format!("{HOME_PREFIX}{owner}")
+    } else {
+        format!("{HOME_PREFIX}{owner}/{path}")
+    })
+}
+
+/// Sums up what `user_id` may do at `relative` in the home directory of
+/// `owner_id`, including everything granted on its ancestors and to the groups
+/// that the user is a member of.
+pub fn permissions(
+    owner_id: i32,
+    relative: &Path,
+    user_id: i32,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Permissions> {
+    use crate::schema::{acl, group_acl, group_members};
+
+    let mut entries: Vec<(String, i32)> = acl::table
+        .filter(acl::owner_id.eq(owner_id))
+        .filter(acl::user_id.eq(user_id))
+        .select((acl::path, acl::permissions))
+        .load(db)
+        .context("query database")?;
+    let groups = group_members::table
+        .filter(group_members::user_id.eq(user_id))
+        .select(group_members::group_id);
+    entries.extend(
+        group_acl::table
+            .filter(group_acl::owner_id.eq(owner_id))
+            .filter(group_acl::group_id.eq_any(groups))
+            .select((group_acl::path, group_acl::permissions))
+            .load::<(String, i32)>(db)
+            .context("query database")?,
+    );
+    let mask = entries
+        .iter()
+        .filter(|(path, _)| relative.starts_with(path))
+        .fold(0, |mask, (_, permissions)| mask | permissions);
+    Ok(Permissions(mask))
+}
+
+/// Replaces whatever `principal` was granted at `path` before.
+pub fn grant(
+    owner_id: i32,
+    path: &str,
+    principal: Principal,
+    permissions: Permissions,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(!permissions.is_empty(), "no permissions specified");
+    match principal {
+        Principal::User(user_id) => diesel::replace_into(crate::schema::acl::table)
+            .values(&NewAclEntry {
+                owner_id,
+                path,
+                user_id,
+                permissions: permissions.0,
+            })
+            .execute(db),
+        Principal::Group(group_id) => diesel::replace_into(crate::schema::group_acl::table)
+            .values(&NewGroupAclEntry {
+                owner_id,
+                path,
+                group_id,
+                permissions: permissions.0,
+            })
+            .execute(db),
+    }
+    .context("insert into database")?;
+    Ok(())
+}
+
+pub fn revoke(
+    owner_id: i32,
+    path: &str,
+    principal: Principal,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    use crate::schema::{acl, group_acl};
+
+    let num_deleted = match principal {
+        Principal::User(user_id) => diesel::delete(
+            acl::table
+                .filter(acl::owner_id.eq(owner_id))
+                .filter(acl::path.eq(path))
+                .filter(acl::user_id.eq(user_id)),
+        )
+        .execute(db),
+        Principal::Group(group_id) => diesel::delete(
+            group_acl::table
+                .filter(group_acl::owner_id.eq(owner_id))
+                .filter(group_acl::path.eq(path))
+                .filter(group_acl::group_id.eq(group_id)),
+        )
+        .execute(db),
+    }
+    .context("delete from database")?;
+    anyhow::ensure!(num_deleted > 0, "no access was granted at {path:?}");
+    Ok(())
+}
+
+/// Lists everything that `owner_id` has granted.
+pub fn list_grants(owner_id: i32, db: &mut SqliteConnection) -> anyhow::Result<Vec<Grant>> {
+    use crate::schema::{acl, group_acl};
+
+    let entries: Vec<AclEntry> = acl::table
+        .filter(acl::owner_id.eq(owner_id))
+        .order(acl::path)
+        .load(db)
+        .context("query database")?;
+    let group_entries: Vec<GroupAclEntry> = group_acl::table
+        .filter(group_acl::owner_id.eq(owner_id))
+        .order(group_acl::path)
+        .load(db)
+        .context("query database")?;
+
+    let mut grants = Vec::with_capacity(entries.len() + group_entries.len());
+    for entry in entries {
+        grants.push(Grant {
+            username: Some(crate::user::find_username(entry.user_id, db)?),
+            group: None,
+            path: entry.path,
+            permissions: Permissions(entry.permissions),
+        });
+    }
+    for entry in group_entries {
+        grants.push(Grant {
+            username: None,
+            group: Some(crate::group::find_name(entry.group_id, db)?),
+            path: entry.path,
+            permissions: Permissions(entry.permissions),
+        });
+    }
+    Ok(grants)
+}
+
+/// Lists the directories that others have shared with `user_id`, directly or
+/// through a group.
+pub fn list_shared(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<Vec<SharedEntry>> {
+    use crate::schema::{acl, group_acl, group_members};
+
+    let entries: Vec<AclEntry> = acl::table
+        .filter(acl::user_id.eq(user_id))
+        .order((acl::owner_id, acl::path))
+        .load(db)
+        .context("query database")?;
+    let groups = group_members::table
+        .filter(group_members::user_id.eq(user_id))
+        .select(group_members::group_id);
+    let group_entries: Vec<GroupAclEntry> = group_acl::table
+        .filter(group_acl::group_id.eq_any(groups))
+        // Their own directories are no news to them
+        .filter(group_acl::owner_id.ne(user_id))
+        .order((group_acl::owner_id, group_acl::path))
+        .load(db)
+        .context("query database")?;
+
+    let mut shared = Vec::with_capacity(entries.len() + group_entries.len());
+    for entry in entries {
+        shared.push(SharedEntry {
+            path: owner_web_path(entry.owner_id, &entry.path, db)?,
+            group: None,
+            permissions: Permissions(entry.permissions),
+        });
+    }
+    for entry in group_entries {
+        shared.push(SharedEntry {
+            path: owner_web_path(entry.owner_id, &entry.path, db)?,
+            group: Some(crate::group::find_name(entry.group_id, db)?),
+            permissions: Permissions(entry.permissions),
+        });
+    }
+    Ok(shared)
+}
+
+type GrantPaths = Vec<(i32, String)>;
+
+/// IDs and paths of what `owner_id` has granted at `relative` or below it,
+/// to users and to groups.
+fn grants_within(
+    owner_id: i32,
+    relative: &Path,
+    db: &mut SqliteConnection,
+) -> diesel::QueryResult<(GrantPaths, GrantPaths)> {
+    use crate::schema::{acl, group_acl};
+
+    let within = |(_, path): &(i32, String)| Path::new(path).starts_with(relative);
+    let entries = acl::table
+        .filter(acl::owner_id.eq(owner_id))
+        .select((acl::id, acl::path))
+        .load::<(i32, String)>(db)?
+        .into_iter()
+        .filter(within)
+        .collect();
+    let group_entries = group_acl::table
+        .filter(group_acl::owner_id.eq(owner_id))
+        .select((group_acl::id, group_acl::path))
+        .load::<(i32, String)>(db)?
+        .into_iter()
+        .filter(within)
+        .collect();
+    Ok((entries, group_entries))
+}
+
+fn delete_within(
+    owner_id: i32,
+    relative: &Path,
+    db: &mut SqliteConnection,
+) -> diesel::QueryResult<()> {
+    use crate::schema::{acl, group_acl};
+
+    let (entries, group_entries) = grants_within(owner_id, relative, db)?;
+    let ids = entries.into_iter().map(|(id, _)| id);
+    diesel::delete(acl::table.filter(acl::id.eq_any(ids))).execute(db)?;
+    let ids = group_entries.into_iter().map(|(id, _)| id);
+    diesel::delete(group_acl::table.filter(group_acl::id.eq_any(ids))).execute(db)?;
+    Ok(())
+}
+
+/// Lists the paths at `relative` or below it that `owner_id` has granted
+/// access to.
+pub fn roots_within(
+    owner_id: i32,
+    relative: &Path,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let (entries, group_entries) =
+        grants_within(owner_id, relative, db).context("query database")?;
+    let mut roots: Vec<PathBuf> = entries
+        .into_iter()
+        .chain(group_entries)
+        .map(|(_, path)| path.into())
+        .collect();
+    roots.sort();
+    roots.dedup();
+    Ok(roots)
+}
+
+/// Makes what was granted at `from` and below it follow the entry to `to`,
+/// replacing whatever was granted at `to` before.
+pub fn move_grants(
+    owner_id: i32,
+    from: &Path,
+    to: &Path,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    use crate::schema::{acl, group_acl};
+
+    let moved = |path: &str| to_web_path(&to.join(Path::new(path).strip_prefix(from).unwrap()));
+    db.transaction(|db| {
+        delete_within(owner_id, to, db)?;
+        let (entries, group_entries) = grants_within(owner_id, from, db)?;
+        for (id, path) in entries {
+            diesel::update(acl::table.find(id))
+                .set(acl::path.eq(moved(&path)))
+                .execute(db)?;
+        }
+        for (id, path) in group_entries {
+            diesel::update(group_acl::table.find(id))
+                .set(group_acl::path.eq(moved(&path)))
+                .execute(db)?;
+        }
+        Ok::<_, diesel::result::Error>(())
+    })
+    .context("update database")
+}
+
+/// Deletes what was granted at `relative` and below it, once it is gone.
+pub fn delete_grants(
+    owner_id: i32,
+    relative: &Path,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    db.transaction(|db| delete_within(owner_id, relative, db))
+        .context("delete from database")
+}
+
+/// Deletes everything that `user_id` has granted or been granted directly.
+pub fn delete_all(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::{acl, group_acl};
+
+    diesel::delete(acl::table.filter(acl::owner_id.eq(user_id).or(acl::user_id.eq(user_id))))
+        .execute(db)
+        .context("delete from database")?;
+    diesel::delete(group_acl::table.filter(group_acl::owner_id.eq(user_id)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use diesel::{RunQueryDsl, SqliteConnection};
+
+    use super::{
+        delete_grants, grant, move_grants, permissions, roots_within, Permission, Permissions,
+        Principal,
+    };
+    use crate::models::NewUser;
+
+    fn add_user(username: &str, db: &mut SqliteConnection) -> i32 {
+        diesel::insert_into(crate::schema::users::table)
+            .values(&NewUser {
+                username,
+                hashed_pass: "",
+                role: "user",
+            })
+            .execute(db)
+            .unwrap();
+        crate::user::find_id(username, db).unwrap()
+    }
+
+    fn granted(owner_id: i32, path: &str, user_id: i32, db: &mut SqliteConnection) -> Permissions {
+        permissions(owner_id, Path::new(path), user_id, db).unwrap()
+    }
+
+    #[test]
+    fn from_list_contains_listed() {
+        let permissions = Permissions::from_list(&[Permission::Read, Permission::Share]);
+        assert!(permissions.contains(Permission::Read));
+        assert!(permissions.contains(Permission::Share));
+        assert!(!permissions.contains(Permission::Upload));
+        assert!(!permissions.contains(Permission::Modify));
+        assert!(!permissions.is_empty());
+    }
+
+    #[test]
+    fn from_list_ignores_duplicates() {
+        assert_eq!(
+            Permissions::from_list(&[Permission::Upload, Permission::Upload]),
+            Permissions::from_list(&[Permission::Upload]),
+        );
+        assert!(Permissions::from_list(&[]).is_empty());
+    }
+
+    #[test]
+    fn permissions_inherited_from_ancestors() {
+        let mut db = crate::db::in_memory();
+        let owner = add_user("owner", &mut db);
+        let user = add_user("user", &mut db);
+        let read = Permissions::from_list(&[Permission::Read]);
+        grant(owner, "docs", Principal::User(user), read, &mut db).unwrap();
+
+        assert_eq!(granted(owner, "docs", user, &mut db), read);
+        assert_eq!(granted(owner, "docs/a/b", user, &mut db), read);
+        assert!(granted(owner, "", user, &mut db).is_empty());
+        assert!(granted(owner, "other", user, &mut db).is_empty());
+        // Only whole segments count
+        assert!(granted(owner, "docsx", user, &mut db).is_empty());
+        // Nor does it carry over to anybody else's home directory
+        assert!(granted(user, "docs", owner, &mut db).is_empty());
+    }
+
+    #[test]
+    fn permissions_combine_user_and_group_grants() {
+        let mut db = crate::db::in_memory();
+        let owner = add_user("owner", &mut db);
+        let member = add_user("member", &mut db);
+        let outsider = add_user("outsider", &mut db);
+        crate::group::create("team", &mut db).unwrap();
+        let group = crate::group::find_id("team", &mut db).unwrap();
+        crate::group::add_member(group, member, &mut db).unwrap();
+
+        let read = Permissions::from_list(&[Permission::Read]);
+        let contribute = Permissions::from_list(&[Permission::Upload, Permission::Share]);
+        grant(owner, "", Principal::User(member), read, &mut db).unwrap();
+        grant(owner, "docs", Principal::Group(group), contribute, &mut db).unwrap();
+
+        assert_eq!(
+            granted(owner, "docs/a", member, &mut db),
+            Permissions::from_list(&[Permission::Read, Permission::Upload, Permission::Share]),
+        );
+        assert_eq!(granted(owner, "music", member, &mut db), read);
+        assert!(granted(owner, "docs/a", outsider, &mut db).is_empty());
+    }
+
+    #[test]
+    fn grants_follow_moved_entries() {
+        let mut db = crate::db::in_memory();
+        let owner = add_user("owner", &mut db);
+        let user = add_user("user", &mut db);
+        crate::group::create("team", &mut db).unwrap();
+        let group = crate::group::find_id("team", &mut db).unwrap();
+        let read = Permissions::from_list(&[Permission::Read]);
+        let modify = Permissions::from_list(&[Permission::Modify]);
+        grant(owner, "docs", Principal::User(user), read, &mut db).unwrap();
+        grant(owner, "docs/a", Principal::Group(group), modify, &mut db).unwrap();
+        grant(owner, "docsx", Principal::User(user), modify, &mut db).unwrap();
+        grant(owner, "archive/old", Principal::User(user), modify, &mut db).unwrap();
+
+        move_grants(owner, Path::new("docs"), Path::new("archive/old"), &mut db).unwrap();
+
+        assert_eq!(
+            roots_within(owner, Path::new(""), &mut db).unwrap(),
+            [
+                Path::new("archive/old"),
+                Path::new("archive/old/a"),
+                Path::new("docsx")
+            ],
+        );
+        // Whatever was granted at the destination before is replaced
+        assert_eq!(granted(owner, "archive/old/b", user, &mut db), read);
+        assert!(granted(owner, "docs", user, &mut db).is_empty());
+        assert_eq!(granted(owner, "docsx", user, &mut db), modify);
+    }
+
+    #[test]
+    fn grants_deleted_with_entries() {
+        let mut db = crate::db::in_memory();
+        let owner = add_user("owner", &mut db);
+        let user = add_user("user", &mut db);
+        crate::group::create("team", &mut db).unwrap();
+        let group = crate::group::find_id("team", &mut db).unwrap();
+        crate::group::add_member(group, user, &mut db).unwrap();
+        let read = Permissions::from_list(&[Permission::Read]);
+        grant(owner, "docs", Principal::User(user), read, &mut db).unwrap();
+        grant(owner, "docs/a", Principal::Group(group), read, &mut db).unwrap();
+        grant(owner, "docsx", Principal::User(user), read, &mut db).unwrap();
+
+        delete_grants(owner, Path::new("docs"), &mut db).unwrap();
+
+        assert!(granted(owner, "docs/a", user, &mut db).is_empty());
+        assert_eq!(
+            roots_within(owner, Path::new(""), &mut db).unwrap(),
+            [Path::new("docsx")],
+        );
+        // Nothing is left to appear again on a new entry of the same name
+        assert!(roots_within(owner, Path::new("docs"), &mut db)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,52 @@
+use diesel::SqliteConnection;
+use serde::Deserialize;
+
+/// What happens to the files of a user who is deleted.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePolicy {
+    /// Delete the home directory along with the account.
+    Delete,
+    /// Move the home directory into that of another user, in a directory
+    /// named after the deleted user.
+    Transfer,
+}
+
+/// Deletes a user's account and everything attached to it.
+///
+/// With [`FilePolicy::Transfer`], `transfer_to` receives the files. The trash
+/// is deleted either way.
+pub async fn delete_user(
+    user_id: i32,
+    files: FilePolicy,
+    transfer_to: i32,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let username = crate::user::find_username(user_id, db)?;
+
+    // Files first, so that a failure leaves the account for another attempt
+    match files {
+        FilePolicy::Delete => crate::home::remove_home(user_id).await?,
+        FilePolicy::Transfer => {
+            anyhow::ensure!(
+                transfer_to != user_id,
+                "cannot transfer files to the user being deleted",
+            );
+            crate::home::transfer_home(user_id, transfer_to, &username).await?
+        }
+    }
+    crate::trash::remove_all(user_id, db).await?;
+
+    for upload_info in crate::links::delete_all(user_id, db)? {
+        if upload_info.started && !upload_info.finished {
+            let staged = crate::api::upload::staging_file(&upload_info.uuid);
+            if let Err(err) = async_std::fs::remove_file(&staged).await {
+                log::warn!("Failed to remove unfinished upload {staged:?}: {err}");
+            }
+        }
+    }
+    crate::acl::delete_all(user_id, db)?;
+    crate::invite::delete_all(user_id, db)?;
+    crate::group::remove_from_all(user_id, db)?;
+    crate::user::delete(user_id, db)
+}
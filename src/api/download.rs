@@ -1,19 +1,63 @@
-use std::time::{Duration, Instant};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 use actix_files::NamedFile;
 use actix_web::{
     get,
-    http::StatusCode,
-    web::{Data, Path as WebPath},
-    ResponseError,
+    http::{
+        header::{
+            self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+        },
+        StatusCode,
+    },
+    post,
+    web::{Data, Form, Path as WebPath},
+    HttpRequest, HttpResponse, ResponseError,
 };
+use anyhow::Context;
+use serde::Deserialize;
 use uuid::Uuid;
 
 use crate::{
-    safe_path::normalize_web_path_as_file,
-    state::{AppState, DownloadInfo},
+    acl::Permission,
+    archive::ArchiveFormat,
+    home::{file_to_web_path, UserContext},
+    links::ShareOptions,
+    state::AppState,
 };
 
+/// Maximum number of entries in a multi-selection download.
+const MAX_SELECTION: usize = 1000;
+
+/// How many passwords may be tried on a link before it is locked.
+const MAX_UNLOCK_ATTEMPTS: u32 = 5;
+
+/// How long a link stays locked after too many incorrect passwords.
+const UNLOCK_LOCKOUT: Duration = Duration::from_secs(60);
+
+/// Served in place of the file when the link is protected by a password.
+const UNLOCK_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Password required</title>
+</head>
+<body>
+<p>This download is protected by a password.</p>
+<p style="color: red">{message}</p>
+<form method="post">
+<input type="password" name="password" placeholder="Password" autofocus required>
+<button type="submit">Download</button>
+</form>
+</body>
+</html>
+"#;
+
 #[derive(thiserror::Error, Debug)]
 pub enum DownloadError {
     #[error("Parse UUID failed: {0}")]
@@ -22,8 +66,17 @@ pub enum DownloadError {
     #[error("The download link you specified does not exist, or has expired.")]
     LinkNotExists,
 
+    #[error("The download link you specified has reached its download limit.")]
+    LimitReached,
+
     #[error("File I/O error: {0}")]
     ServeFile(std::io::Error),
+
+    #[error("Failed to prepare archive: {0:#}")]
+    Archive(anyhow::Error),
+
+    #[error("Database error: {0:#}")]
+    Database(anyhow::Error),
 }
 
 impl ResponseError for DownloadError {
@@ -31,56 +84,405 @@ impl ResponseError for DownloadError {
         match self {
             DownloadError::ParseUuid(_) => StatusCode::BAD_REQUEST,
             DownloadError::LinkNotExists => StatusCode::NOT_FOUND,
+            DownloadError::LimitReached => StatusCode::GONE,
             DownloadError::ServeFile(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            DownloadError::Archive(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            DownloadError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Recent password attempts on each download link.
+#[derive(Default)]
+pub struct UnlockAttempts {
+    attempts: Mutex<HashMap<Uuid, (u32, Instant)>>,
+}
+
+impl UnlockAttempts {
+    /// Counts an attempt on the link, returning `false` if it is locked.
+    ///
+    /// Attempts are counted before the password is checked, so that parallel
+    /// guesses are limited too.
+    fn start(&self, uuid: Uuid) -> bool {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, (_, last)| now.duration_since(*last) < UNLOCK_LOCKOUT);
+        let (num_attempts, last) = attempts.entry(uuid).or_insert((0, now));
+        if *num_attempts >= MAX_UNLOCK_ATTEMPTS {
+            return false;
+        }
+        *num_attempts += 1;
+        *last = now;
+        true
+    }
+
+    fn succeeded(&self, uuid: &Uuid) {
+        self.attempts.lock().unwrap().remove(uuid);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    password: String,
+}
+
+fn attachment(file_name: String) -> ContentDisposition {
+    let param = if file_name.is_ascii() {
+        DispositionParam::Filename(file_name)
+    } else {
+        DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_owned()),
+            language_tag: None,
+            value: file_name.into_bytes(),
+        })
+    };
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![param],
+    }
+}
+
+/// Streams `file` (a file or a directory) as an archive, or only the selected
+/// entries if `selection` is specified.
+async fn serve_archive(
+    file: &str,
+    selection: Option<&str>,
+    format: ArchiveFormat,
+) -> Result<HttpResponse, DownloadError> {
+    let web_path = file_to_web_path(file);
+    let name = web_path.rsplit('/').next().filter(|name| !name.is_empty());
+    let name = name.unwrap_or("files");
+
+    let roots: Vec<(PathBuf, String)> = match selection {
+        None => vec![(file.into(), name.to_owned())],
+        Some(selection) => {
+            let paths: Vec<PathBuf> = serde_json::from_str(selection)
+                .context("parse selected entries")
+                .map_err(DownloadError::Database)?;
+            let names = crate::archive::unique_names(&paths);
+            paths.into_iter().zip(names).collect()
+        }
+    };
+    let mut entries = Vec::new();
+    for (root, name) in &roots {
+        entries.extend(
+            crate::archive::collect_entries(root, name)
+                .await
+                .map_err(DownloadError::Archive)?,
+        );
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(attachment(format!("{name}.{}", format.as_str())))
+        .streaming(crate::archive::stream(format, entries)))
+}
+
+/// Whether a request fetches the file from its start, as opposed to resuming
+/// a download that has already been counted.
+fn starts_download(req: &HttpRequest) -> bool {
+    match req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+    {
+        None => true,
+        Some(range) => match range.trim().strip_prefix("bytes=") {
+            Some(specs) => specs.trim_start().starts_with("0-"),
+            // Not a range that could be served anyway
+            None => true,
+        },
+    }
+}
+
+fn unlock_page(status: StatusCode, message: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type("text/html; charset=utf-8")
+        .body(UNLOCK_PAGE.replace("{message}", message))
+}
+
+async fn serve(
+    req: &HttpRequest,
+    uuid: &str,
+    password: Option<&str>,
+    state: &Data<AppState>,
+) -> Result<HttpResponse, DownloadError> {
+    let uuid = Uuid::parse_str(uuid).map_err(DownloadError::ParseUuid)?;
+    let mut db = state
+        .db
+        .get()
+        .context("obtain database connection")
+        .map_err(DownloadError::Database)?;
+    let download_info = crate::links::find_download(&uuid, &mut db)
+        .map_err(DownloadError::Database)?
+        .ok_or(DownloadError::LinkNotExists)?;
+
+    if let Some(hashed_pass) = &download_info.hashed_pass {
+        match password {
+            None => return Ok(unlock_page(StatusCode::OK, "")),
+            Some(password) => {
+                if !state.unlock_attempts.start(uuid) {
+                    log::debug!("Too many wrong passwords for download link {uuid}");
+                    return Ok(unlock_page(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "Too many incorrect passwords. Please try again later.",
+                    ));
+                }
+                if crate::user::pwhash_verify(password, hashed_pass).is_err() {
+                    log::debug!("Wrong password for download link {uuid}");
+                    return Ok(unlock_page(StatusCode::FORBIDDEN, "Incorrect password."));
+                }
+                state.unlock_attempts.succeeded(&uuid);
+            }
+        }
+    }
+
+    let archive = download_info.archive.as_deref().map(|format| {
+        ArchiveFormat::parse(format).ok_or_else(|| {
+            DownloadError::Database(anyhow::anyhow!("unknown archive format {format:?}"))
+        })
+    });
+    match archive.transpose()? {
+        Some(format) => {
+            let response = serve_archive(
+                &download_info.file,
+                download_info.entries.as_deref(),
+                format,
+            )
+            .await?;
+            if !crate::links::count_download(&uuid, &mut db).map_err(DownloadError::Database)? {
+                return Err(DownloadError::LimitReached);
+            }
+            Ok(response)
+        }
+        None => {
+            let file = NamedFile::open(&download_info.file).map_err(DownloadError::ServeFile)?;
+            // Resuming a download must not count against the limit again, but
+            // nothing is served once the limit has been reached
+            let limit_reached = if starts_download(req) || download_info.num_downloads == 0 {
+                !crate::links::count_download(&uuid, &mut db).map_err(DownloadError::Database)?
+            } else {
+                download_info
+                    .max_downloads
+                    .is_some_and(|max_downloads| download_info.num_downloads >= max_downloads)
+            };
+            if limit_reached {
+                return Err(DownloadError::LimitReached);
+            }
+            Ok(file.into_response(req))
         }
     }
 }
 
 #[get("/file/{uuid}")]
 pub async fn download(
+    req: HttpRequest,
     uuid: WebPath<String>,
     state: Data<AppState>,
-) -> Result<NamedFile, DownloadError> {
-    let uuid = Uuid::parse_str(&uuid).map_err(DownloadError::ParseUuid)?;
-    let mut lock = state.downloads.lock().await;
-    if let Some(download_info) = lock.get(&uuid) {
-        if Instant::now() < download_info.expires {
-            Ok(NamedFile::open(&download_info.file).map_err(DownloadError::ServeFile)?)
-        } else {
-            log::debug!("Download link {uuid} has expired");
-            lock.remove(&uuid);
-            Err(DownloadError::LinkNotExists)
-        }
+) -> Result<HttpResponse, DownloadError> {
+    serve(&req, &uuid, None, &state).await
+}
+
+#[post("/file/{uuid}")]
+pub async fn unlock(
+    req: HttpRequest,
+    uuid: WebPath<String>,
+    form: Form<UnlockForm>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, DownloadError> {
+    serve(&req, &uuid, Some(&form.password), &state).await
+}
+
+/// Generates a download link for a file, or for an archive of a file or
+/// directory if `format` is specified.
+///
+/// Directories are always served as archives, defaulting to ZIP.
+pub async fn gen_download_uuid(
+    ctx: &UserContext,
+    web_path: &str,
+    format: Option<ArchiveFormat>,
+    options: &ShareOptions<'_>,
+    state: &Data<AppState>,
+) -> anyhow::Result<String> {
+    let mut db = state.db.get().context("obtain database connection")?;
+    // The link works without an account
+    let normalized = ctx.authorize(web_path, Permission::Share, &mut db)?;
+    let metadata = async_std::fs::metadata(&normalized).await?;
+    let format = if metadata.is_dir() {
+        Some(format.unwrap_or(ArchiveFormat::Zip))
     } else {
-        Err(DownloadError::LinkNotExists)
+        anyhow::ensure!(
+            metadata.is_file(),
+            "the path specified does not point to a file or directory",
+        );
+        format
+    };
+
+    let uuid = crate::links::create_download(
+        ctx.user_id,
+        normalized.to_str().unwrap(),
+        format,
+        None,
+        options,
+        &mut db,
+    )?;
+    log::debug!("Generated UUID {uuid} for download {normalized:?}");
+    Ok(uuid)
+}
+
+/// Generates a download link for a single archive of all selected entries.
+pub async fn gen_selection_uuid(
+    ctx: &UserContext,
+    web_paths: &[String],
+    format: Option<ArchiveFormat>,
+    options: &ShareOptions<'_>,
+    state: &Data<AppState>,
+) -> anyhow::Result<String> {
+    anyhow::ensure!(!web_paths.is_empty(), "no entries selected");
+    anyhow::ensure!(
+        web_paths.len() <= MAX_SELECTION,
+        "cannot select more than {MAX_SELECTION} entries",
+    );
+
+    let mut db = state.db.get().context("obtain database connection")?;
+    let mut selection: Vec<PathBuf> = Vec::with_capacity(web_paths.len());
+    for web_path in web_paths {
+        let normalized = ctx.authorize(web_path, Permission::Share, &mut db)?;
+        async_std::fs::metadata(&normalized)
+            .await
+            .with_context(|| format!("{web_path:?} does not exist"))?;
+        if !selection.contains(&normalized) {
+            selection.push(normalized);
+        }
     }
+
+    // Entries inside a selected directory are archived with it already
+    let selected = selection.clone();
+    selection.retain(|path| {
+        !selected
+            .iter()
+            .any(|other| other != path && path.starts_with(other))
+    });
+
+    // Store the link against the closest common ancestor, which also names the
+    // archive
+    let mut common = selection[0].parent().unwrap_or(&selection[0]).to_owned();
+    while !selection.iter().all(|path| path.starts_with(&common)) {
+        common.pop();
+    }
+
+    let entries: Vec<String> = selection
+        .iter()
+        .map(|path| path.to_str().unwrap().to_owned())
+        .collect();
+    let uuid = crate::links::create_download(
+        ctx.user_id,
+        common.to_str().unwrap(),
+        Some(format.unwrap_or(ArchiveFormat::Zip)),
+        Some(&entries),
+        options,
+        &mut db,
+    )?;
+    log::debug!(
+        "Generated UUID {uuid} for download of {} entries",
+        entries.len()
+    );
+    Ok(uuid)
 }
 
-pub async fn gen_download_uuid(web_path: &str, state: &Data<AppState>) -> anyhow::Result<String> {
-    let normalized = normalize_web_path_as_file(web_path)?;
-    let metadata = async_std::fs::metadata(&normalized).await?;
-    if metadata.is_file() {
-        let mut lock = state.downloads.lock().await;
-        let mut num_tries = 0;
-        while num_tries < 20 {
-            num_tries += 1;
-            let uuid = uuid::Builder::from_random_bytes(rand::random()).into_uuid();
-            if lock.contains_key(&uuid) {
-                continue;
-            }
-            let uuid_str = uuid.to_string();
-            log::debug!("Generated UUID {uuid_str} for download {normalized:?}");
-            lock.insert(
-                uuid,
-                DownloadInfo {
-                    file: normalized,
-                    expires: Instant::now() + Duration::from_secs(24 * 60 * 60),
-                },
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, test::TestRequest, web::Data};
+    use diesel::RunQueryDsl;
+    use uuid::Uuid;
+
+    use super::{serve, DownloadError};
+    use crate::{links::ShareOptions, models::NewUser, state::AppState};
+
+    /// Creates a link to a file with `max_downloads`, and counts `num_downloads`
+    /// against it.
+    fn link(max_downloads: i32, num_downloads: i32) -> (Data<AppState>, String) {
+        let state = Data::new(AppState {
+            db: crate::db::in_memory_pool(),
+            uploads: Default::default(),
+            unlock_attempts: Default::default(),
+            settings: Default::default(),
+        });
+        let mut db = state.db.get().unwrap();
+        diesel::insert_into(crate::schema::users::table)
+            .values(&NewUser {
+                username: "alice",
+                hashed_pass: "",
+                role: "user",
+            })
+            .execute(&mut db)
+            .unwrap();
+        let user_id = crate::user::find_id("alice", &mut db).unwrap();
+
+        let file = std::env::temp_dir().join(format!(
+            "download-{}.txt",
+            uuid::Builder::from_random_bytes(rand::random()).into_uuid()
+        ));
+        std::fs::write(&file, "0123456789").unwrap();
+        let options = ShareOptions {
+            max_downloads: Some(max_downloads),
+            ..ShareOptions::default()
+        };
+        let uuid = crate::links::create_download(
+            user_id,
+            file.to_str().unwrap(),
+            None,
+            None,
+            &options,
+            &mut db,
+        )
+        .unwrap();
+        for _ in 0..num_downloads {
+            let uuid = Uuid::parse_str(&uuid).unwrap();
+            assert!(crate::links::count_download(&uuid, &mut db).unwrap());
+        }
+        drop(db);
+        (state, uuid)
+    }
+
+    fn ranged(range: &str) -> actix_web::HttpRequest {
+        TestRequest::default()
+            .insert_header((header::RANGE, range))
+            .to_http_request()
+    }
+
+    #[actix_web::test]
+    async fn resumed_download_is_not_counted() {
+        let (state, uuid) = link(2, 1);
+        let response = serve(&ranged("bytes=4-"), &uuid, None, &state)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 206);
+
+        let uuid = Uuid::parse_str(&uuid).unwrap();
+        let mut db = state.db.get().unwrap();
+        let download_info = crate::links::find_download(&uuid, &mut db)
+            .unwrap()
+            .unwrap();
+        assert_eq!(download_info.num_downloads, 1);
+    }
+
+    #[actix_web::test]
+    async fn exhausted_link_rejects_ranged_request() {
+        let (state, uuid) = link(1, 1);
+        for range in ["bytes=4-", "bytes=0-", "bytes=-2"] {
+            let result = serve(&ranged(range), &uuid, None, &state).await;
+            assert!(
+                matches!(result, Err(DownloadError::LinkNotExists)),
+                "{range}"
             );
-            return Ok(uuid_str);
         }
-        anyhow::bail!("failed to allocate a UUID for this download");
-    } else {
-        anyhow::bail!("the path specified does not point to a file");
+        let result = serve(
+            &TestRequest::default().to_http_request(),
+            &uuid,
+            None,
+            &state,
+        )
+        .await;
+        assert!(matches!(result, Err(DownloadError::LinkNotExists)));
     }
 }
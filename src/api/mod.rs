@@ -1,8 +1,13 @@
 pub mod download;
+pub mod tus;
 pub mod upload;
 
 pub fn all_apis() -> actix_web::Scope {
     actix_web::web::scope("/api")
         .service(download::download)
+        .service(download::unlock)
         .service(upload::upload)
+        .service(upload::upload_put)
+        .service(upload::upload_form)
+        .service(tus::tus_apis())
 }
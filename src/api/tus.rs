@@ -0,0 +1,396 @@
+//! The [tus](https://tus.io/protocols/resumable-upload) resumable upload
+//! protocol, for clients that cannot use the WebSocket uploader.
+//!
+//! Uploads are authorized by the same upload links: the link's UUID is either
+//! passed as `uuid` in the `Upload-Metadata` of a creation request, or used
+//! directly as the upload URL `/api/tus/{uuid}`.
+
+use actix_web::{
+    delete,
+    dev::HttpServiceFactory,
+    head,
+    http::{header::ContentType, StatusCode},
+    middleware::DefaultHeaders,
+    options, patch, post,
+    web::{Data, Path as WebPath, Payload},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use anyhow::Context;
+use base64::Engine;
+use futures_util::StreamExt;
+use uuid::Uuid;
+
+use crate::{
+    api::upload::{SharedUpload, UploadError},
+    checksum::{HashAlgorithm, Hasher},
+    models::UploadInfo,
+    range_set::RangeSet,
+    state::AppState,
+};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,checksum";
+const TUS_CHECKSUM_ALGORITHMS: &str = "sha1,sha256,blake3";
+
+#[derive(thiserror::Error, Debug)]
+pub enum TusError {
+    #[error("Only tus version {TUS_VERSION} is supported.")]
+    UnsupportedVersion,
+
+    #[error("Missing header {0}.")]
+    MissingHeader(&'static str),
+
+    #[error("Invalid header {0}.")]
+    InvalidHeader(&'static str),
+
+    #[error("Upload-Metadata must contain the UUID of an upload link as `uuid`.")]
+    MissingUuid,
+
+    #[error("Parse UUID failed: {0}")]
+    ParseUuid(uuid::Error),
+
+    #[error("The upload link you specified does not exist, or has expired.")]
+    LinkNotExists,
+
+    #[error("Upload-Length does not match the size of the upload link.")]
+    LengthMismatch,
+
+    #[error("The upload has already been finished.")]
+    AlreadyFinished,
+
+    #[error("PATCH requests must have the content type application/offset+octet-stream.")]
+    UnsupportedMediaType,
+
+    #[error("Upload-Offset does not match the current offset {0}.")]
+    OffsetMismatch(u64),
+
+    #[error("The checksum algorithm is not supported.")]
+    UnsupportedChecksum,
+
+    #[error("The checksum of the uploaded data does not match.")]
+    ChecksumMismatch,
+
+    #[error("Failed to open upload: {0}")]
+    Open(UploadError),
+
+    #[error("Failed to write upload: {0:#}")]
+    Write(anyhow::Error),
+
+    #[error("Failed to finish upload: {0:#}")]
+    Finish(anyhow::Error),
+
+    #[error("Database error: {0:#}")]
+    Database(anyhow::Error),
+}
+
+impl ResponseError for TusError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TusError::UnsupportedVersion => StatusCode::PRECONDITION_FAILED,
+            TusError::MissingHeader(_) => StatusCode::BAD_REQUEST,
+            TusError::InvalidHeader(_) => StatusCode::BAD_REQUEST,
+            TusError::MissingUuid => StatusCode::BAD_REQUEST,
+            TusError::ParseUuid(_) => StatusCode::BAD_REQUEST,
+            TusError::LinkNotExists => StatusCode::NOT_FOUND,
+            TusError::LengthMismatch => StatusCode::BAD_REQUEST,
+            TusError::AlreadyFinished => StatusCode::CONFLICT,
+            TusError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            TusError::OffsetMismatch(_) => StatusCode::CONFLICT,
+            TusError::UnsupportedChecksum => StatusCode::BAD_REQUEST,
+            // Defined by the checksum extension
+            TusError::ChecksumMismatch => StatusCode::from_u16(460).unwrap(),
+            TusError::Open(err) => err.status_code(),
+            TusError::Write(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            TusError::Finish(_) => StatusCode::CONFLICT,
+            TusError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if let TusError::UnsupportedVersion = self {
+            res.insert_header(("Tus-Version", TUS_VERSION));
+        }
+        res.content_type(ContentType::plaintext())
+            .body(self.to_string())
+    }
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &'static str) -> Result<Option<&'a str>, TusError> {
+    req.headers()
+        .get(name)
+        .map(|value| value.to_str().map_err(|_| TusError::InvalidHeader(name)))
+        .transpose()
+}
+
+fn header_u64(req: &HttpRequest, name: &'static str) -> Result<u64, TusError> {
+    header(req, name)?
+        .ok_or(TusError::MissingHeader(name))?
+        .parse()
+        .map_err(|_| TusError::InvalidHeader(name))
+}
+
+fn check_version(req: &HttpRequest) -> Result<(), TusError> {
+    match header(req, "Tus-Resumable")? {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(TusError::UnsupportedVersion),
+    }
+}
+
+/// Extracts the upload UUID from `Upload-Metadata`, which is a comma
+/// separated list of keys and base64 encoded values.
+fn metadata_uuid(req: &HttpRequest) -> Result<Uuid, TusError> {
+    let metadata = header(req, "Upload-Metadata")?.ok_or(TusError::MissingUuid)?;
+    let value = metadata
+        .split(',')
+        .filter_map(|pair| pair.trim().split_once(' '))
+        .find(|(key, _)| *key == "uuid")
+        .ok_or(TusError::MissingUuid)?
+        .1;
+    let value = base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(|_| TusError::InvalidHeader("Upload-Metadata"))?;
+    let value =
+        std::str::from_utf8(&value).map_err(|_| TusError::InvalidHeader("Upload-Metadata"))?;
+    Uuid::parse_str(value).map_err(TusError::ParseUuid)
+}
+
+/// Parses `Upload-Checksum`, which names the algorithm and gives the base64
+/// encoded digest.
+fn parse_checksum(value: &str) -> Result<(HashAlgorithm, Vec<u8>), TusError> {
+    let (algorithm, digest) = value
+        .split_once(' ')
+        .ok_or(TusError::InvalidHeader("Upload-Checksum"))?;
+    let algorithm = HashAlgorithm::parse(algorithm).ok_or(TusError::UnsupportedChecksum)?;
+    let digest = base64::engine::general_purpose::STANDARD
+        .decode(digest)
+        .map_err(|_| TusError::InvalidHeader("Upload-Checksum"))?;
+    Ok((algorithm, digest))
+}
+
+fn find_upload(uuid: &Uuid, state: &AppState) -> Result<UploadInfo, TusError> {
+    let mut db = state
+        .db
+        .get()
+        .context("obtain database connection")
+        .map_err(TusError::Database)?;
+    crate::links::find_upload(uuid, &mut db)
+        .map_err(TusError::Database)?
+        .ok_or(TusError::LinkNotExists)
+}
+
+async fn open_upload(upload_info: UploadInfo, state: &AppState) -> Result<SharedUpload, TusError> {
+    let mut db = state
+        .db
+        .get()
+        .context("obtain database connection")
+        .map_err(TusError::Database)?;
+    state
+        .uploads
+        .open(upload_info, &mut db)
+        .await
+        .map_err(TusError::Open)
+}
+
+#[options("")]
+pub async fn discover() -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Checksum-Algorithm", TUS_CHECKSUM_ALGORITHMS))
+        .finish()
+}
+
+#[post("")]
+pub async fn create(req: HttpRequest, state: Data<AppState>) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let length = header_u64(&req, "Upload-Length")?;
+    let uuid = metadata_uuid(&req)?;
+    let upload_info = find_upload(&uuid, &state)?;
+    if upload_info.finished {
+        return Err(TusError::AlreadyFinished);
+    }
+    if length != upload_info.size as u64 {
+        return Err(TusError::LengthMismatch);
+    }
+
+    Ok(HttpResponse::Created()
+        .insert_header((
+            "Location",
+            format!("{}/{uuid}", req.path().trim_end_matches('/')),
+        ))
+        .finish())
+}
+
+/// Returns the offset that an upload nobody is connected to would be resumed
+/// from, without touching its staged file.
+async fn persisted_offset(upload_info: &UploadInfo) -> u64 {
+    let staged = crate::api::upload::staging_file(&upload_info.uuid);
+    if !upload_info.started || async_std::fs::metadata(&staged).await.is_err() {
+        return 0;
+    }
+    let size = upload_info.size as u64;
+    serde_json::from_str::<RangeSet>(&upload_info.received)
+        .ok()
+        .and_then(|received| received.missing(size).first().map(|range| range.start))
+        .unwrap_or(size)
+}
+
+#[head("/{uuid}")]
+pub async fn status(
+    req: HttpRequest,
+    uuid: WebPath<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let uuid = Uuid::parse_str(&uuid).map_err(TusError::ParseUuid)?;
+    let upload_info = find_upload(&uuid, &state)?;
+    let size = upload_info.size as u64;
+    let offset = if upload_info.finished {
+        size
+    } else if let Some(shared) = state.uploads.get(&uuid).await {
+        shared.lock().await.offset()
+    } else {
+        persisted_offset(&upload_info).await
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Upload-Offset", offset.to_string()))
+        .insert_header(("Upload-Length", size.to_string()))
+        .insert_header(("Cache-Control", "no-store"))
+        .finish())
+}
+
+/// Writes the request body at `offset`, returning the new offset.
+async fn write_body(
+    shared: &SharedUpload,
+    size: u64,
+    offset: u64,
+    checksum: Option<(HashAlgorithm, Vec<u8>)>,
+    mut payload: Payload,
+    state: &AppState,
+) -> Result<u64, TusError> {
+    // Hold the upload until the body has been written, so that parallel
+    // requests cannot both write at the same offset
+    let mut upload = shared.lock().await;
+    if upload.offset() != offset {
+        return Err(TusError::OffsetMismatch(upload.offset()));
+    }
+
+    let mut checksum = checksum.map(|(algorithm, digest)| (Hasher::new(algorithm), digest));
+    let mut pos = offset;
+    let mut interrupted = None;
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                log::debug!("Upload body interrupted: {err}");
+                interrupted = Some(TusError::Write(anyhow::anyhow!("body interrupted: {err}")));
+                break;
+            }
+        };
+        if let Some((hasher, _)) = &mut checksum {
+            hasher.update(&chunk);
+        }
+        upload
+            .write_raw(pos, &chunk)
+            .await
+            .map_err(TusError::Write)?;
+        pos += chunk.len() as u64;
+    }
+
+    // Whatever arrived before an interruption is kept, unless it cannot be
+    // checked against the checksum
+    if let Some((hasher, expected)) = checksum {
+        if let Some(err) = interrupted {
+            return Err(err);
+        }
+        if hasher.finalize() != expected {
+            return Err(TusError::ChecksumMismatch);
+        }
+    }
+
+    upload
+        .mark_received(offset..pos, state)
+        .await
+        .map_err(TusError::Write)?;
+    upload.persist(state).await.map_err(TusError::Write)?;
+    if let Some(err) = interrupted {
+        return Err(err);
+    }
+
+    // There is no explicit request to finish a tus upload
+    let offset = upload.offset();
+    if offset == size {
+        upload.finish(state).await.map_err(TusError::Finish)?;
+    }
+    Ok(offset)
+}
+
+#[patch("/{uuid}")]
+pub async fn append(
+    req: HttpRequest,
+    payload: Payload,
+    uuid: WebPath<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    if header(&req, "Content-Type")? != Some("application/offset+octet-stream") {
+        return Err(TusError::UnsupportedMediaType);
+    }
+    let offset = header_u64(&req, "Upload-Offset")?;
+    let checksum = header(&req, "Upload-Checksum")?
+        .map(parse_checksum)
+        .transpose()?;
+
+    let uuid = Uuid::parse_str(&uuid).map_err(TusError::ParseUuid)?;
+    let upload_info = find_upload(&uuid, &state)?;
+    if upload_info.finished {
+        return Err(TusError::AlreadyFinished);
+    }
+    let size = upload_info.size as u64;
+    let shared = open_upload(upload_info, &state).await?;
+    let result = write_body(&shared, size, offset, checksum, payload, &state).await;
+    state.uploads.release(&uuid, shared).await;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Upload-Offset", result?.to_string()))
+        .finish())
+}
+
+#[delete("/{uuid}")]
+pub async fn terminate(
+    req: HttpRequest,
+    uuid: WebPath<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let uuid = Uuid::parse_str(&uuid).map_err(TusError::ParseUuid)?;
+    let upload_info = find_upload(&uuid, &state)?;
+    state.uploads.evict(&uuid).await;
+    let mut db = state
+        .db
+        .get()
+        .context("obtain database connection")
+        .map_err(TusError::Database)?;
+    crate::links::delete_upload(&uuid, &mut db).map_err(TusError::Database)?;
+
+    if upload_info.started && !upload_info.finished {
+        let staged = crate::api::upload::staging_file(&upload_info.uuid);
+        if let Err(err) = async_std::fs::remove_file(&staged).await {
+            log::warn!("Failed to remove terminated upload {staged:?}: {err}");
+        }
+    }
+    log::debug!("Upload {uuid} terminated");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn tus_apis() -> impl HttpServiceFactory {
+    actix_web::web::scope("/tus")
+        .wrap(DefaultHeaders::new().add(("Tus-Resumable", TUS_VERSION)))
+        .service(discover)
+        .service(create)
+        .service(status)
+        .service(append)
+        .service(terminate)
+}
@@ -1,35 +1,94 @@
-use std::time::{Duration, Instant};
-
+use actix_multipart::Multipart;
 use actix_web::{
     get,
-    http::StatusCode,
-    web::{Data, Path as WebPath},
+    http::{header::CONTENT_LENGTH, StatusCode},
+    post, put,
+    web::{Bytes, Data, Path as WebPath, Payload, Query},
     HttpRequest, HttpResponse, ResponseError,
 };
-use actix_ws::{Item, Message, MessageStream, Session as WsSession};
+use actix_ws::{CloseCode, CloseReason, Item, Message, MessageStream, Session as WsSession};
 use anyhow::Context;
 use async_std::{
-    fs::File,
+    fs::{File, OpenOptions},
     io::{prelude::SeekExt, WriteExt},
+    sync::Mutex,
+};
+use diesel::SqliteConnection;
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use futures_util::StreamExt;
+
+use futures_util::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
-    safe_path::normalize_web_path_as_file,
-    state::{AppState, UploadInfo},
+    acl::Permission,
+    checksum::Checksum,
+    heartbeat::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL},
+    home::UserContext,
+    models::UploadInfo,
+    range_set::RangeSet,
+    state::AppState,
 };
 
-struct Session {
+/// Uploads are written here and only moved to their destination once
+/// finished, so that they never show up half-written.
+const STAGING_DIR: &str = "uploads";
+
+/// Longest fragmented text message accepted, since commands are short.
+const MAX_TEXT_LEN: usize = 64 << 10; // 64 KiB
+
+/// How often the received ranges are saved while data is coming in.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn staging_file(uuid: &str) -> PathBuf {
+    Path::new(STAGING_DIR).join(uuid)
+}
+
+/// An upload in progress, shared by all connections to its link.
+pub struct Upload {
     file: File,
     size: u64,
+    received: RangeSet,
+    last_persisted: Instant,
+    checksum: Option<Checksum>,
+    finished: bool,
+    uuid: Uuid,
+    target: PathBuf,
+}
+
+pub type SharedUpload = Arc<Mutex<Upload>>;
+
+/// Uploads that at least one client is connected to.
+///
+/// A client may reconnect before the server notices that its previous
+/// connection is gone, so all connections to a link must share one state.
+#[derive(Default)]
+pub struct ActiveUploads {
+    uploads: Mutex<HashMap<Uuid, SharedUpload>>,
+}
+
+/// A connection to an upload link.
+struct Session {
+    upload: SharedUpload,
+    pos: u64,
+    /// Whether binary messages start with the offset of their data.
+    offset_frames: bool,
+    size: u64,
+    uuid: Uuid,
+    state: Data<AppState>,
 }
 
 #[derive(Deserialize)]
 #[serde(tag = "cmd")]
 enum Request {
     Seek { pos: u64 },
+    Status {},
     Finish {},
 }
 
@@ -38,6 +97,7 @@ enum Request {
 enum Response {
     Empty {},
     BlockReceived { len: u64, cur_pos: u64 },
+    Status { size: u64, missing: Vec<Range<u64>> },
 }
 
 #[derive(Serialize)]
@@ -67,19 +127,169 @@ impl Session {
         match req {
             Request::Seek { pos } => {
                 anyhow::ensure!(pos <= self.size, "cannot seek past end of file");
-                self.file.seek(std::io::SeekFrom::Start(pos)).await?;
+                self.pos = pos;
                 Ok(Response::Empty {})
             }
+            Request::Status {} => Ok(Response::Status {
+                size: self.size,
+                missing: self.upload.lock().await.received.missing(self.size),
+            }),
             Request::Finish {} => {
-                self.file.flush().await?;
+                self.upload.lock().await.finish(&self.state).await?;
                 Ok(Response::Empty {})
             }
         }
     }
 
-    pub async fn write_data(&mut self, data: &[u8]) -> anyhow::Result<Response> {
-        let cur = self.file.seek(std::io::SeekFrom::Current(0)).await?;
-        let write_len = data.len().min((self.size - cur) as usize);
+    pub async fn execute_text(&mut self, text: &[u8]) -> anyhow::Result<Response> {
+        let req = serde_json::from_slice(text).context("parse JSON")?;
+        self.execute_req(req).await
+    }
+
+    /// Writes a fragment of a binary message, or all of an unfragmented one.
+    ///
+    /// With offset frames, each message starts with the 64-bit big-endian
+    /// offset of its data, so that several connections can upload different
+    /// parts of the file at the same time. The offset may itself be split
+    /// across fragments.
+    async fn write_fragment(&mut self, msg: &mut BinaryMessage, mut data: &[u8]) {
+        if msg.err.is_some() {
+            return;
+        }
+        if self.offset_frames && msg.offset.len() < 8 {
+            let num_bytes = data.len().min(8 - msg.offset.len());
+            msg.offset.extend_from_slice(&data[..num_bytes]);
+            data = &data[num_bytes..];
+            if msg.offset.len() < 8 {
+                return;
+            }
+            let offset = u64::from_be_bytes(msg.offset[..].try_into().unwrap());
+            if offset > self.size {
+                msg.err = Some(anyhow::anyhow!("offset past end of file"));
+                return;
+            }
+            self.pos = offset;
+        }
+        if data.is_empty() {
+            return;
+        }
+
+        let mut inner = self.upload.lock().await;
+        match inner.write_at(self.pos, data, &self.state).await {
+            Ok(len) => {
+                self.pos += len;
+                msg.len += len;
+            }
+            Err(err) => msg.err = Some(err),
+        }
+    }
+
+    /// Reports on a binary message once its last fragment has been written.
+    fn finish_message(&self, msg: BinaryMessage) -> anyhow::Result<Response> {
+        if let Some(err) = msg.err {
+            return Err(err);
+        }
+        anyhow::ensure!(
+            !self.offset_frames || msg.offset.len() == 8,
+            "message too short to contain an offset",
+        );
+        anyhow::ensure!(msg.len > 0, "message contains no data");
+        Ok(Response::BlockReceived {
+            len: msg.len,
+            cur_pos: self.pos,
+        })
+    }
+
+    async fn write_data(&mut self, data: &[u8]) -> anyhow::Result<Response> {
+        let mut msg = BinaryMessage::default();
+        self.write_fragment(&mut msg, data).await;
+        self.finish_message(msg)
+    }
+
+    /// Saves the progress and forgets the upload if this was the last
+    /// connection to it.
+    async fn close(self) {
+        let mut inner = self.upload.lock().await;
+        if !inner.finished {
+            if let Err(err) = inner.persist(&self.state).await {
+                log::warn!("Failed to save progress of upload {}: {err:#}", self.uuid);
+            }
+        }
+        drop(inner);
+        self.state.uploads.release(&self.uuid, self.upload).await;
+    }
+}
+
+impl Upload {
+    /// Moves the staged file to its destination, provided that every byte
+    /// has been received and the checksum matches.
+    ///
+    /// Otherwise the staged data is discarded, so that the upload can be
+    /// restarted from scratch.
+    pub async fn finish(&mut self, state: &AppState) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.finished, "upload already finished");
+        self.file.flush().await?;
+        if let Err(err) = self.verify().await {
+            self.discard(state).await.context("discard staged data")?;
+            return Err(err.context("staged data discarded"));
+        }
+
+        self.file.sync_data().await.context("sync staged file")?;
+        anyhow::ensure!(
+            async_std::fs::symlink_metadata(&self.target).await.is_err(),
+            "the destination has been created by someone else in the meantime",
+        );
+        async_std::fs::rename(staging_file(&self.uuid.to_string()), &self.target)
+            .await
+            .context("move staged file to destination")?;
+        self.finished = true;
+
+        let mut db = state.db.get().context("obtain database connection")?;
+        crate::links::set_upload_progress(&self.uuid, true, true, &mut db)?;
+        log::debug!("Upload {} finished as {:?}", self.uuid, self.target);
+        Ok(())
+    }
+
+    async fn verify(&self) -> anyhow::Result<()> {
+        let missing = self.received.missing(self.size);
+        anyhow::ensure!(
+            missing.is_empty(),
+            "upload incomplete: {} bytes in {} ranges have not been received",
+            missing.iter().map(|r| r.end - r.start).sum::<u64>(),
+            missing.len(),
+        );
+
+        if let Some(checksum) = self.checksum.clone() {
+            let staged = staging_file(&self.uuid.to_string());
+            actix_web::web::block(move || checksum.verify_file(&staged))
+                .await
+                .context("hash staged file")??;
+        }
+        Ok(())
+    }
+
+    async fn discard(&mut self, state: &AppState) -> anyhow::Result<()> {
+        log::debug!("Discarding staged data of upload {}", self.uuid);
+        self.file.set_len(0).await?;
+        self.file.set_len(self.size).await?;
+        self.received = RangeSet::new();
+        self.persist(state).await
+    }
+
+    /// Syncs the staged file and saves the received ranges, so that the
+    /// upload can be resumed after a disconnect or a server restart.
+    pub async fn persist(&mut self, state: &AppState) -> anyhow::Result<()> {
+        self.file.flush().await?;
+        self.file.sync_data().await.context("sync staged file")?;
+        let mut db = state.db.get().context("obtain database connection")?;
+        crate::links::set_upload_received(&self.uuid, &self.received, &mut db)?;
+        self.last_persisted = Instant::now();
+        Ok(())
+    }
+
+    /// Writes `data` at `pos`, returning how many bytes were written.
+    async fn write_at(&mut self, pos: u64, data: &[u8], state: &AppState) -> anyhow::Result<u64> {
+        let write_len = data.len().min((self.size - pos) as usize);
         if write_len < data.len() {
             log::warn!(
                 "An uploader sent {} stray bytes! Ignoring",
@@ -91,64 +301,239 @@ impl Session {
             anyhow::bail!("already reached end of file");
         }
 
+        self.write_raw(pos, data).await?;
+        self.mark_received(pos..pos + write_len as u64, state)
+            .await?;
+        Ok(write_len as u64)
+    }
+
+    /// Writes `data` at `pos` without recording it as received yet.
+    pub async fn write_raw(&mut self, pos: u64, data: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.finished, "upload already finished");
+        anyhow::ensure!(
+            pos + data.len() as u64 <= self.size,
+            "data exceeds the size of the upload",
+        );
+        self.file.seek(std::io::SeekFrom::Start(pos)).await?;
         self.file.write_all(data).await?;
-        Ok(Response::BlockReceived {
-            len: write_len as u64,
-            cur_pos: cur + write_len as u64,
-        })
+        Ok(())
+    }
+
+    pub async fn mark_received(
+        &mut self,
+        range: Range<u64>,
+        state: &AppState,
+    ) -> anyhow::Result<()> {
+        self.received.insert(range);
+        if self.last_persisted.elapsed() >= PERSIST_INTERVAL {
+            self.persist(state).await.context("save upload progress")?;
+        }
+        Ok(())
+    }
+
+    /// Returns how many bytes from the start of the file have been received
+    /// without any gaps.
+    pub fn offset(&self) -> u64 {
+        self.received
+            .missing(self.size)
+            .first()
+            .map_or(self.size, |range| range.start)
     }
 }
 
-async fn worker(file: File, size: u64, mut ws_session: WsSession, mut msg_stream: MessageStream) {
-    let mut session = Session { file, size };
+impl ActiveUploads {
+    /// Returns the state of the upload, reopening its staged file if no
+    /// client is connected to it yet.
+    pub async fn open(
+        &self,
+        upload_info: UploadInfo,
+        db: &mut SqliteConnection,
+    ) -> Result<SharedUpload, UploadError> {
+        let uuid = Uuid::parse_str(&upload_info.uuid).map_err(UploadError::ParseUuid)?;
+        let mut uploads = self.uploads.lock().await;
+        if let Some(shared) = uploads.get(&uuid) {
+            return Ok(shared.clone());
+        }
 
-    while let Some(msg) = msg_stream.next().await {
-        match msg {
-            // Dirty fix: we assume all Continuation packets are caused by
-            // binary packets, and we do not track each packet's preceding
-            // packet.
-            // FIXME: better implementation
-            Ok(Message::Binary(data)) | Ok(Message::Continuation(Item::Last(data))) => {
-                let result = session.write_data(&data).await;
-                if let Err(err) = &result {
-                    log::debug!("Failed to process incoming data: {err:#}");
+        async_std::fs::create_dir_all(STAGING_DIR)
+            .await
+            .map_err(UploadError::PrepareFile)?;
+        let staged = staging_file(&upload_info.uuid);
+        let size = upload_info.size as u64;
+
+        // Continue where the previous connection left off, if its data is
+        // still there
+        let resumed = if upload_info.started {
+            match OpenOptions::new().write(true).open(&staged).await {
+                Ok(file) => Some(file),
+                Err(err) => {
+                    log::warn!("Restarting upload {uuid}, failed to reopen {staged:?}: {err}");
+                    None
                 }
-                if let Err(err) = ws_session
-                    .text(serde_json::to_string(&JsonResponse::from(result)).unwrap())
+            }
+        } else {
+            None
+        };
+        let (file, received) = match resumed {
+            Some(file) => {
+                let received = serde_json::from_str(&upload_info.received)
+                    .context("parse received ranges")
+                    .map_err(UploadError::Database)?;
+                (file, received)
+            }
+            None => {
+                let file = File::create(&staged)
                     .await
-                {
-                    log::error!("Failed to send response to client: {err:#}");
-                }
+                    .map_err(UploadError::PrepareFile)?;
+                file.set_len(size).await.map_err(UploadError::PrepareFile)?;
+                let received = RangeSet::new();
+                crate::links::set_upload_received(&uuid, &received, db)
+                    .map_err(UploadError::Database)?;
+                (file, received)
+            }
+        };
+        crate::links::set_upload_progress(&uuid, true, false, db).map_err(UploadError::Database)?;
+
+        let shared = Arc::new(Mutex::new(Upload {
+            file,
+            size,
+            received,
+            last_persisted: Instant::now(),
+            checksum: upload_info
+                .checksum
+                .as_deref()
+                .and_then(Checksum::parse_stored),
+            finished: false,
+            uuid,
+            target: upload_info.file.into(),
+        }));
+        uploads.insert(uuid, shared.clone());
+        Ok(shared)
+    }
+
+    /// Returns the state of the upload if a client is connected to it.
+    pub async fn get(&self, uuid: &Uuid) -> Option<SharedUpload> {
+        self.uploads.lock().await.get(uuid).cloned()
+    }
+
+    /// Forgets the upload right away and keeps the clients still connected to
+    /// it from writing to it any more.
+    pub async fn evict(&self, uuid: &Uuid) {
+        let shared = self.uploads.lock().await.remove(uuid);
+        if let Some(shared) = shared {
+            // Also stops the state from being persisted when they disconnect
+            shared.lock().await.finished = true;
+        }
+    }
+
+    /// Forgets the upload once the last connection to it has been closed.
+    pub async fn release(&self, uuid: &Uuid, shared: SharedUpload) {
+        let mut uploads = self.uploads.lock().await;
+        // One reference is held by the map, the other one is ours
+        if Arc::strong_count(&shared) <= 2 {
+            uploads.remove(uuid);
+        }
+    }
+}
+
+/// A binary message being written as it arrives.
+#[derive(Default)]
+struct BinaryMessage {
+    /// The offset prefix, as far as it has been received.
+    offset: Vec<u8>,
+    /// Bytes written so far.
+    len: u64,
+    /// Once a fragment fails, the rest of the message is skipped and the
+    /// error is reported at its end.
+    err: Option<anyhow::Error>,
+}
+
+/// A message whose first fragment has been received, but not its last one.
+enum Fragmented {
+    /// Text is collected until the message is complete.
+    Text(Vec<u8>),
+    Binary(BinaryMessage),
+}
+
+async fn send_response(ws_session: &mut WsSession, result: anyhow::Result<Response>) {
+    if let Err(err) = &result {
+        log::debug!("Failed to process message: {err:#}");
+    }
+    if let Err(err) = ws_session
+        .text(serde_json::to_string(&JsonResponse::from(result)).unwrap())
+        .await
+    {
+        log::error!("Failed to send response to client: {err:#}");
+    }
+}
+
+fn protocol_error(description: &str) -> Option<CloseReason> {
+    log::debug!("Closing upload connection: {description}");
+    Some(CloseReason {
+        code: CloseCode::Protocol,
+        description: Some(description.to_owned()),
+    })
+}
+
+async fn worker(mut session: Session, mut ws_session: WsSession, mut msg_stream: MessageStream) {
+    let mut fragmented = None;
+    let mut close_reason = None;
+    while let Some(msg) = msg_stream.next().await {
+        match msg {
+            Ok(Message::Binary(_)) | Ok(Message::Text(_)) if fragmented.is_some() => {
+                close_reason = protocol_error("new message before the last one ended");
+                break;
             }
-            // Dirty fix: we assume all Continuation packets are caused by
-            // binary packets, and we do not track each packet's preceding
-            // packet.
-            // FIXME: better implementation
-            Ok(Message::Continuation(Item::FirstBinary(data)))
-            | Ok(Message::Continuation(Item::Continue(data))) => {
+            Ok(Message::Binary(data)) => {
                 let result = session.write_data(&data).await;
-                if let Err(err) = &result {
-                    log::debug!("Failed to process incoming data: {err:#}");
-                }
-                // Don't send a response now, since the packet hasn't ended yet
+                send_response(&mut ws_session, result).await;
             }
             Ok(Message::Text(text)) => {
-                let result = serde_json::from_str(text.as_ref()).context("parse JSON");
-                let result = match result {
-                    Ok(req) => session.execute_req(req).await,
-                    Err(err) => Err(err),
-                };
-                if let Err(err) = &result {
-                    log::debug!("Failed to process request: {err:#}");
+                let result = session.execute_text(text.as_bytes()).await;
+                send_response(&mut ws_session, result).await;
+            }
+            Ok(Message::Continuation(Item::FirstText(data))) => {
+                fragmented = Some(Fragmented::Text(data.to_vec()));
+            }
+            Ok(Message::Continuation(Item::FirstBinary(data))) => {
+                let mut msg = BinaryMessage::default();
+                session.write_fragment(&mut msg, &data).await;
+                fragmented = Some(Fragmented::Binary(msg));
+            }
+            Ok(Message::Continuation(Item::Continue(data))) => match &mut fragmented {
+                Some(Fragmented::Text(text)) if text.len() + data.len() > MAX_TEXT_LEN => {
+                    close_reason = Some(CloseReason {
+                        code: CloseCode::Size,
+                        description: Some("text message too long".to_owned()),
+                    });
+                    break;
                 }
-                if let Err(err) = ws_session
-                    .text(serde_json::to_string(&JsonResponse::from(result)).unwrap())
-                    .await
-                {
-                    log::error!("Failed to send response to client: {err:#}");
+                Some(Fragmented::Text(text)) => text.extend_from_slice(&data),
+                Some(Fragmented::Binary(msg)) => session.write_fragment(msg, &data).await,
+                None => {
+                    close_reason = protocol_error("continuation without a message");
+                    break;
                 }
+            },
+            Ok(Message::Continuation(Item::Last(data))) => {
+                let result = match fragmented.take() {
+                    Some(Fragmented::Text(mut text)) => {
+                        text.extend_from_slice(&data);
+                        session.execute_text(&text).await
+                    }
+                    Some(Fragmented::Binary(mut msg)) => {
+                        session.write_fragment(&mut msg, &data).await;
+                        session.finish_message(msg)
+                    }
+                    None => {
+                        close_reason = protocol_error("continuation without a message");
+                        break;
+                    }
+                };
+                send_response(&mut ws_session, result).await;
             }
-            Ok(Message::Pong(_)) => (),
+            Ok(Message::Ping(_) | Message::Pong(_)) => (),
+            Ok(Message::Close(_)) => break,
             Ok(other) => {
                 log::debug!("Ignoring unknown WebSocket message: {other:?}");
             }
@@ -159,7 +544,8 @@ async fn worker(file: File, size: u64, mut ws_session: WsSession, mut msg_stream
         }
     }
     log::debug!("Client closed connection");
-    let _ = ws_session.close(None).await;
+    session.close().await;
+    let _ = ws_session.close(close_reason).await;
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -170,11 +556,35 @@ pub enum UploadError {
     #[error("The upload link you specified does not exist, or has expired.")]
     LinkNotExists,
 
+    #[error("The upload has already been finished.")]
+    AlreadyFinished,
+
     #[error("File I/O error: {0}")]
     PrepareFile(std::io::Error),
 
+    #[error("Database error: {0:#}")]
+    Database(anyhow::Error),
+
     #[error("WebSocket error: {0}")]
     WebSocket(actix_web::Error),
+
+    #[error("The request body is larger than the upload.")]
+    TooLarge,
+
+    #[error("Upload incomplete: received {received} of {size} bytes.")]
+    Incomplete { received: u64, size: u64 },
+
+    #[error("The form contains no file.")]
+    NoFile,
+
+    #[error("Failed to receive request body: {0}")]
+    Receive(String),
+
+    #[error("File I/O error: {0:#}")]
+    Write(anyhow::Error),
+
+    #[error("Failed to finish upload: {0:#}")]
+    Finish(anyhow::Error),
 }
 
 impl ResponseError for UploadError {
@@ -182,75 +592,227 @@ impl ResponseError for UploadError {
         match self {
             UploadError::ParseUuid(_) => StatusCode::BAD_REQUEST,
             UploadError::LinkNotExists => StatusCode::NOT_FOUND,
+            UploadError::AlreadyFinished => StatusCode::CONFLICT,
             UploadError::PrepareFile(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            UploadError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
             UploadError::WebSocket(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            UploadError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            UploadError::Incomplete { .. } => StatusCode::BAD_REQUEST,
+            UploadError::NoFile => StatusCode::BAD_REQUEST,
+            UploadError::Receive(_) => StatusCode::BAD_REQUEST,
+            UploadError::Write(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            UploadError::Finish(_) => StatusCode::CONFLICT,
         }
     }
 }
 
-#[get("/uploads/{uuid}")]
-pub async fn upload(
+/// Looks up an upload link that can still be uploaded to.
+fn find_unfinished(
+    uuid: &str,
+    db: &mut SqliteConnection,
+) -> Result<(Uuid, UploadInfo), UploadError> {
+    let uuid = Uuid::parse_str(uuid).map_err(UploadError::ParseUuid)?;
+    let upload_info = crate::links::find_upload(&uuid, db)
+        .map_err(UploadError::Database)?
+        .ok_or(UploadError::LinkNotExists)?;
+    if upload_info.finished {
+        return Err(UploadError::AlreadyFinished);
+    }
+    Ok((uuid, upload_info))
+}
+
+/// Writes `body` from the start of the file, returning its length.
+async fn write_body<S, E>(
+    shared: &SharedUpload,
+    size: u64,
+    mut body: S,
+    state: &AppState,
+) -> Result<u64, UploadError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut pos = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|err| UploadError::Receive(err.to_string()))?;
+        let len = chunk.len() as u64;
+        if pos + len > size {
+            return Err(UploadError::TooLarge);
+        }
+
+        let mut inner = shared.lock().await;
+        inner
+            .write_raw(pos, &chunk)
+            .await
+            .map_err(UploadError::Write)?;
+        inner
+            .mark_received(pos..pos + len, state)
+            .await
+            .map_err(UploadError::Write)?;
+        pos += len;
+    }
+    Ok(pos)
+}
+
+/// Receives the whole file in one request body and finishes the upload.
+///
+/// Whatever has been received is kept on failure, so that the upload can
+/// still be resumed over a WebSocket or tus.
+async fn upload_body<S, E>(
+    upload_info: UploadInfo,
+    body: S,
+    state: &AppState,
+) -> Result<(), UploadError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let uuid = Uuid::parse_str(&upload_info.uuid).map_err(UploadError::ParseUuid)?;
+    let size = upload_info.size as u64;
+    // Pooled connections are scarce, so none is held while the body arrives
+    let shared = {
+        let mut db = state
+            .db
+            .get()
+            .context("obtain database connection")
+            .map_err(UploadError::Database)?;
+        state.uploads.open(upload_info, &mut db).await?
+    };
+
+    let result = write_body(&shared, size, body, state).await;
+    let mut inner = shared.lock().await;
+    let result = match result {
+        Ok(received) if received < size => Err(UploadError::Incomplete { received, size }),
+        Ok(_) => inner.finish(state).await.map_err(UploadError::Finish),
+        Err(err) => Err(err),
+    };
+    if result.is_err() && !inner.finished {
+        if let Err(err) = inner.persist(state).await {
+            log::warn!("Failed to save progress of upload {uuid}: {err:#}");
+        }
+    }
+    drop(inner);
+    state.uploads.release(&uuid, shared).await;
+    result
+}
+
+#[put("/uploads/{uuid}")]
+pub async fn upload_put(
     req: HttpRequest,
-    stream: actix_web::web::Payload,
+    payload: Payload,
     uuid: WebPath<String>,
     state: Data<AppState>,
 ) -> Result<HttpResponse, UploadError> {
-    let uuid = Uuid::parse_str(&uuid).map_err(UploadError::ParseUuid)?;
-    let mut lock = state.uploads.lock().await;
-    if let Some(upload_info) = lock.get(&uuid) {
-        if Instant::now() < upload_info.expires {
-            let file = File::create(&upload_info.file)
-                .await
-                .map_err(UploadError::PrepareFile)?;
-            let size = upload_info.size;
-            file.set_len(size).await.map_err(UploadError::PrepareFile)?;
-
-            let (res, session, msg_stream) =
-                actix_ws::handle(&req, stream).map_err(UploadError::WebSocket)?;
-            actix_web::rt::spawn(worker(file, size, session, msg_stream));
-            Ok(res)
-        } else {
-            log::debug!("Upload link {uuid} has expired");
-            lock.remove(&uuid);
-            Err(UploadError::LinkNotExists)
+    let (_, upload_info) = {
+        let mut db = state
+            .db
+            .get()
+            .context("obtain database connection")
+            .map_err(UploadError::Database)?;
+        find_unfinished(&uuid, &mut db)?
+    };
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > upload_info.size as u64) {
+        return Err(UploadError::TooLarge);
+    }
+
+    upload_body(upload_info, payload, &state).await?;
+    Ok(HttpResponse::Ok().body("Upload finished.\n"))
+}
+
+/// Receives the first file of a `multipart/form-data` form.
+#[post("/uploads/{uuid}")]
+pub async fn upload_form(
+    mut form: Multipart,
+    uuid: WebPath<String>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, UploadError> {
+    let (_, upload_info) = {
+        let mut db = state
+            .db
+            .get()
+            .context("obtain database connection")
+            .map_err(UploadError::Database)?;
+        find_unfinished(&uuid, &mut db)?
+    };
+
+    while let Some(field) = form.next().await {
+        let field = field.map_err(|err| UploadError::Receive(err.to_string()))?;
+        if field.content_disposition().get_filename().is_some() {
+            upload_body(upload_info, field, &state).await?;
+            return Ok(HttpResponse::Ok().body("Upload finished.\n"));
         }
-    } else {
-        Err(UploadError::LinkNotExists)
     }
+    Err(UploadError::NoFile)
+}
+
+#[derive(Deserialize)]
+pub struct ConnectOptions {
+    #[serde(default)]
+    offsets: bool,
+}
+
+#[get("/uploads/{uuid}")]
+pub async fn upload(
+    req: HttpRequest,
+    stream: Payload,
+    uuid: WebPath<String>,
+    options: Query<ConnectOptions>,
+    state: Data<AppState>,
+) -> Result<HttpResponse, UploadError> {
+    let mut db = state
+        .db
+        .get()
+        .context("obtain database connection")
+        .map_err(UploadError::Database)?;
+    let (uuid, upload_info) = find_unfinished(&uuid, &mut db)?;
+
+    let (res, ws_session, msg_stream) =
+        actix_ws::handle(&req, stream).map_err(UploadError::WebSocket)?;
+    let size = upload_info.size as u64;
+    let shared = state.uploads.open(upload_info, &mut db).await?;
+    let session = Session {
+        upload: shared,
+        pos: 0,
+        offset_frames: options.offsets,
+        size,
+        uuid,
+        state: state.clone(),
+    };
+    let msg_stream = msg_stream.heartbeat(HEARTBEAT_INTERVAL, CLIENT_TIMEOUT);
+    actix_web::rt::spawn(worker(session, ws_session, msg_stream));
+    Ok(res)
 }
 
 pub async fn gen_upload_uuid(
+    ctx: &UserContext,
     web_path: &str,
     size: u64,
+    checksum: Option<&Checksum>,
     state: &Data<AppState>,
 ) -> anyhow::Result<String> {
-    let normalized = normalize_web_path_as_file(web_path)?;
-    let exists = async_std::path::Path::new(&normalized).exists().await;
-    if !exists {
-        let mut lock = state.uploads.lock().await;
-        let mut num_tries = 0;
-        while num_tries < 20 {
-            num_tries += 1;
-            let uuid = uuid::Builder::from_random_bytes(rand::random()).into_uuid();
-            if lock.contains_key(&uuid) {
-                continue;
-            }
-            let uuid_str = uuid.to_string();
-            log::debug!("Generated UUID {uuid_str} for upload {normalized:?}");
-            // TODO: flock the target file so that no two parallel uploads
-            // could be created
-            lock.insert(
-                uuid,
-                UploadInfo {
-                    file: normalized,
-                    size,
-                    expires: Instant::now() + Duration::from_secs(24 * 60 * 60),
-                },
-            );
-            return Ok(uuid_str);
-        }
-        anyhow::bail!("failed to allocate a UUID for this upload");
-    } else {
-        anyhow::bail!("the path specified already exists");
+    let mut db = state.db.get().context("obtain database connection")?;
+    let normalized = ctx.authorize(web_path, Permission::Upload, &mut db)?;
+    if let Some(checksum) = checksum {
+        checksum.validate()?;
     }
+    let exists = async_std::path::Path::new(&normalized).exists().await;
+    anyhow::ensure!(!exists, "the path specified already exists");
+
+    anyhow::ensure!(
+        !crate::links::upload_pending(normalized.to_str().unwrap(), &mut db)?,
+        "another upload to the path specified is in progress",
+    );
+    let uuid = crate::links::create_upload(
+        ctx.user_id,
+        normalized.to_str().unwrap(),
+        size,
+        checksum,
+        &mut db,
+    )?;
+    log::debug!("Generated UUID {uuid} for upload {normalized:?}");
+    Ok(uuid)
 }
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use actix_web::web::Bytes;
+use anyhow::Context;
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::mpsc::{channel, Sender};
+
+use crate::safe_path::ensure_legal_segment;
+
+/// Size of the chunks sent to the client.
+const CHUNK_SIZE: usize = 64 << 10; // 64 KiB
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum ArchiveFormat {
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<ArchiveFormat> {
+        match s {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar" => Some(ArchiveFormat::Tar),
+            "tar.gz" => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+}
+
+pub struct ArchiveEntry {
+    /// Path inside the archive, separated by `/`.
+    name: String,
+    source: PathBuf,
+    directory: bool,
+    size: u64,
+}
+
+/// Collects `root` and, if it is a directory, everything below it, naming
+/// `root` as `name` inside the archive.
+///
+/// Symbolic links and entries whose names would not be accepted as web paths
+/// are skipped, so that an archive never exposes anything a client could not
+/// have downloaded on its own.
+pub async fn collect_entries(root: &Path, name: &str) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut result = Vec::new();
+    let mut stack = vec![(root.to_owned(), name.to_owned())];
+    while let Some((path, name)) = stack.pop() {
+        let metadata = async_std::fs::symlink_metadata(&path)
+            .await
+            .with_context(|| format!("read metadata of {name:?}"))?;
+        if metadata.is_symlink() {
+            log::warn!("Not archiving symbolic link {path:?}");
+            continue;
+        }
+
+        if metadata.is_dir() {
+            let mut readdir = async_std::fs::read_dir(&path)
+                .await
+                .with_context(|| format!("open directory {name:?}"))?;
+            while let Some(entry) = readdir.next().await {
+                let entry = entry.context("readdir")?;
+                let file_name = entry.file_name();
+                let legal_name = file_name
+                    .to_str()
+                    .filter(|segment| ensure_legal_segment(segment).is_ok());
+                match legal_name {
+                    Some(file_name) => {
+                        stack.push((entry.path().into(), format!("{name}/{file_name}")))
+                    }
+                    None => log::warn!("Not archiving illegally named entry {:?}", entry.path()),
+                }
+            }
+        }
+        result.push(ArchiveEntry {
+            name,
+            source: path,
+            directory: metadata.is_dir(),
+            size: metadata.len(),
+        });
+    }
+
+    // Sorting by name keeps parents before their children
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(result)
+}
+
+/// Names each of `paths` after its last segment, numbering duplicates like
+/// `name (1).ext` so that all names are unique.
+pub fn unique_names(paths: &[PathBuf]) -> Vec<String> {
+    let mut taken = HashSet::new();
+    let mut result = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "files".to_owned());
+        let (stem, ext) = match name.rfind('.') {
+            Some(pos) if pos > 0 => name.split_at(pos),
+            _ => (name.as_str(), ""),
+        };
+
+        let mut unique = name.clone();
+        let mut counter = 0;
+        while !taken.insert(unique.clone()) {
+            counter += 1;
+            unique = format!("{stem} ({counter}){ext}");
+        }
+        result.push(unique);
+    }
+    result
+}
+
+/// Sends everything written to it to the client in chunks.
+struct ChannelWriter {
+    tx: Sender<std::io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send_buf(&mut self) -> std::io::Result<()> {
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client disconnected"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buf()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buf.is_empty() {
+            self.send_buf()?;
+        }
+        Ok(())
+    }
+}
+
+fn write_zip(entries: &[ArchiveEntry], writer: ChannelWriter) -> anyhow::Result<()> {
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    let mut zip = ZipWriter::new_stream(writer);
+    for entry in entries {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .large_file(entry.size >= u32::MAX as u64);
+        if entry.directory {
+            zip.add_directory(&entry.name, options)?;
+        } else {
+            let mut file =
+                File::open(&entry.source).with_context(|| format!("open {:?}", entry.source))?;
+            zip.start_file(&entry.name, options)?;
+            std::io::copy(&mut file, &mut zip)?;
+        }
+    }
+    zip.finish()?.into_inner().flush()?;
+    Ok(())
+}
+
+fn write_tar<W: Write>(entries: &[ArchiveEntry], writer: W) -> anyhow::Result<W> {
+    let mut tar = tar::Builder::new(writer);
+    tar.follow_symlinks(false);
+    for entry in entries {
+        if entry.directory {
+            tar.append_dir(&entry.name, &entry.source)?;
+        } else {
+            let mut file =
+                File::open(&entry.source).with_context(|| format!("open {:?}", entry.source))?;
+            tar.append_file(&entry.name, &mut file)?;
+        }
+    }
+    Ok(tar.into_inner()?)
+}
+
+fn write_archive(
+    format: ArchiveFormat,
+    entries: &[ArchiveEntry],
+    writer: ChannelWriter,
+) -> anyhow::Result<()> {
+    match format {
+        ArchiveFormat::Zip => write_zip(entries, writer),
+        ArchiveFormat::Tar => write_tar(entries, writer)?.flush().map_err(Into::into),
+        ArchiveFormat::TarGz => {
+            let gz = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            write_tar(entries, gz)?
+                .finish()?
+                .flush()
+                .map_err(Into::into)
+        }
+    }
+}
+
+/// Builds the archive on a blocking thread while it is being sent, so that it
+/// never has to be staged on disk.
+pub fn stream(
+    format: ArchiveFormat,
+    entries: Vec<ArchiveEntry>,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let (tx, mut rx) = channel(4);
+    actix_web::rt::task::spawn_blocking(move || {
+        let writer = ChannelWriter {
+            tx: tx.clone(),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        if let Err(err) = write_archive(format, &entries, writer) {
+            log::warn!("Failed to stream {} archive: {err:#}", format.as_str());
+            // Make the client notice the truncated archive
+            let _ = tx.blocking_send(Err(std::io::Error::other(format!("{err:#}"))));
+        }
+    });
+    futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::unique_names;
+
+    #[test]
+    fn unique_names_numbers_duplicates() {
+        let paths: Vec<PathBuf> = ["a/report.pdf", "b/report.pdf", "c/report.pdf", "d/notes"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(
+            unique_names(&paths),
+            ["report.pdf", "report (1).pdf", "report (2).pdf", "notes"],
+        );
+    }
+
+    #[test]
+    fn unique_names_keeps_leading_dot() {
+        let paths: Vec<PathBuf> = [
+            "a/.bashrc",
+            "b/.bashrc",
+            "c/archive.tar.gz",
+            "d/archive.tar.gz",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(
+            unique_names(&paths),
+            [
+                ".bashrc",
+                ".bashrc (1)",
+                "archive.tar.gz",
+                "archive.tar (1).gz"
+            ],
+        );
+    }
+}
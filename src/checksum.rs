@@ -0,0 +1,145 @@
+use std::{fs::File, io::Read, path::Path};
+
+use serde::Deserialize;
+use sha2::Digest;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<HashAlgorithm> {
+        match s {
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Blake3 => blake3::OUT_LEN,
+        }
+    }
+}
+
+/// An expected digest, given as a hex string.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub digest: String,
+}
+
+impl Checksum {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // SHA-1 is only there because the tus checksum extension requires it
+        anyhow::ensure!(
+            self.algorithm != HashAlgorithm::Sha1,
+            "sha1 is not accepted for whole-file checksums",
+        );
+        anyhow::ensure!(
+            self.digest.len() == self.algorithm.digest_len() * 2
+                && self.digest.bytes().all(|b| b.is_ascii_hexdigit()),
+            "{} digest must be {} hex digits",
+            self.algorithm.as_str(),
+            self.algorithm.digest_len() * 2,
+        );
+        Ok(())
+    }
+
+    /// Formats the checksum as stored in the database, e.g. `sha256:0123...`.
+    pub fn to_stored(&self) -> String {
+        format!(
+            "{}:{}",
+            self.algorithm.as_str(),
+            self.digest.to_ascii_lowercase(),
+        )
+    }
+
+    pub fn parse_stored(s: &str) -> Option<Checksum> {
+        let (algorithm, digest) = s.split_once(':')?;
+        Some(Checksum {
+            algorithm: HashAlgorithm::parse(algorithm)?,
+            digest: digest.to_owned(),
+        })
+    }
+
+    /// Hashes the file and compares the result, blocking the current thread.
+    pub fn verify_file(&self, path: &Path) -> anyhow::Result<()> {
+        let actual = to_hex(&hash_file(path, self.algorithm)?);
+        anyhow::ensure!(
+            actual.eq_ignore_ascii_case(&self.digest),
+            "{} mismatch: expected {}, got {actual}",
+            self.algorithm.as_str(),
+            self.digest,
+        );
+        Ok(())
+    }
+}
+
+pub enum Hasher {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Hasher {
+        match algorithm {
+            HashAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+fn hash_file(path: &Path, algorithm: HashAlgorithm) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(hasher.finalize())
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
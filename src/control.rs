@@ -1,21 +1,156 @@
 use actix_web::{web::Data, HttpRequest, HttpResponse};
-use actix_ws::{Message, MessageStream, Session as WsSession};
+use actix_ws::{AggregatedMessage, AggregatedMessageStream, Session as WsSession};
 use anyhow::Context;
-use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 
-use crate::{listdir::DirEntry, state::AppState};
+use crate::{
+    acl::{Grant, Permission, Principal, SharedEntry},
+    admin::FilePolicy,
+    archive::ArchiveFormat,
+    checksum::Checksum,
+    file_ops::DeleteFailure,
+    group::GroupEntry,
+    heartbeat::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL},
+    home::UserContext,
+    invite::InviteEntry,
+    links::{LinkEntry, ShareOptions, MAX_LIFETIME_SECS},
+    listdir::DirEntry,
+    models::TrashItem,
+    state::AppState,
+    user::{Role, UserEntry},
+};
 
 #[derive(Deserialize)]
 #[serde(tag = "cmd")]
 enum Request {
-    LoginPwd { username: String, password: String },
-    RegisterPwd { username: String, password: String },
+    LoginPwd {
+        username: String,
+        password: String,
+    },
+    RegisterPwd {
+        username: String,
+        password: String,
+        invite: Option<String>,
+    },
     Logout {},
-    ListDir { path: String },
-    Download { path: String },
-    Upload { path: String, size: u64 },
-    CreateDir { path: String },
+    ChangePassword {
+        old_password: String,
+        new_password: String,
+    },
+    ListDir {
+        path: String,
+    },
+    Download {
+        path: String,
+        format: Option<ArchiveFormat>,
+    },
+    Upload {
+        path: String,
+        size: u64,
+        checksum: Option<Checksum>,
+    },
+    DownloadSelection {
+        paths: Vec<String>,
+        format: Option<ArchiveFormat>,
+    },
+    CreateShare {
+        path: String,
+        format: Option<ArchiveFormat>,
+        password: Option<String>,
+        expires_in: Option<u64>,
+        max_downloads: Option<u32>,
+    },
+    ListLinks {},
+    RevokeLink {
+        uuid: String,
+    },
+    CreateDir {
+        path: String,
+    },
+    Rename {
+        path: String,
+        name: String,
+        #[serde(default)]
+        overwrite: bool,
+    },
+    Move {
+        from: String,
+        to: String,
+        #[serde(default)]
+        overwrite: bool,
+    },
+    Delete {
+        path: String,
+        #[serde(default)]
+        recursive: bool,
+        #[serde(default)]
+        permanent: bool,
+    },
+    ListTrash {},
+    RestoreTrash {
+        id: i32,
+        path: Option<String>,
+    },
+    PurgeTrash {
+        id: Option<i32>,
+    },
+    Grant {
+        path: String,
+        username: Option<String>,
+        group: Option<String>,
+        permissions: Vec<Permission>,
+    },
+    Revoke {
+        path: String,
+        username: Option<String>,
+        group: Option<String>,
+    },
+    ListGrants {},
+    ListShared {},
+    ListGroups {},
+    CreateGroup {
+        name: String,
+    },
+    DeleteGroup {
+        name: String,
+    },
+    AddGroupMember {
+        group: String,
+        username: String,
+    },
+    RemoveGroupMember {
+        group: String,
+        username: String,
+    },
+    CreateInvite {
+        max_uses: Option<u32>,
+        expires_in: Option<u64>,
+    },
+    ListInvites {},
+    RevokeInvite {
+        code: String,
+    },
+    ListUsers {},
+    DisableUser {
+        username: String,
+    },
+    EnableUser {
+        username: String,
+    },
+    SetRole {
+        username: String,
+        role: Role,
+    },
+    ResetPassword {
+        username: String,
+        password: String,
+    },
+    DeleteUser {
+        username: String,
+        files: FilePolicy,
+        /// Who receives the files, defaulting to the administrator.
+        transfer_to: Option<String>,
+    },
 }
 
 #[derive(Serialize)]
@@ -24,6 +159,15 @@ enum Response {
     Empty {},
     DirList { entries: Vec<DirEntry> },
     DownloadLink { uuid: String },
+    Deleted { failed: Vec<DeleteFailure> },
+    TrashList { entries: Vec<TrashItem> },
+    LinkList { links: Vec<LinkEntry> },
+    GrantList { grants: Vec<Grant> },
+    SharedList { shared: Vec<SharedEntry> },
+    GroupList { groups: Vec<GroupEntry> },
+    UserList { users: Vec<UserEntry> },
+    Invite { code: String },
+    InviteList { invites: Vec<InviteEntry> },
 }
 
 struct Session {
@@ -35,27 +179,109 @@ impl Session {
         Session { user_id: None }
     }
 
+    fn context(&self, state: &AppState) -> anyhow::Result<UserContext> {
+        let user_id = self.user_id.context("not logged in yet")?;
+        Ok(UserContext::new(user_id, state))
+    }
+
+    fn is_admin(&self, state: &AppState) -> anyhow::Result<bool> {
+        let user_id = self.user_id.context("not logged in yet")?;
+        let mut db = state.db.get().context("obtain database connection")?;
+        Ok(crate::user::find_active_role(user_id, &mut db)? == Role::Admin)
+    }
+
+    /// Returns the ID of the logged-in user, if they are an administrator.
+    fn ensure_admin(&self, state: &AppState) -> anyhow::Result<i32> {
+        anyhow::ensure!(self.is_admin(state)?, "only administrators can do this");
+        Ok(self.user_id.unwrap())
+    }
+
+    /// Resolves the user that an administrator wants to manage, who must not
+    /// be the administrator themselves.
+    fn admin_target(&self, username: &str, state: &AppState) -> anyhow::Result<i32> {
+        let admin_id = self.ensure_admin(state)?;
+        let mut db = state.db.get().context("obtain database connection")?;
+        let user_id = crate::user::find_id(username, &mut db)?;
+        anyhow::ensure!(user_id != admin_id, "cannot do this to your own account");
+        Ok(user_id)
+    }
+
+    /// Resolves who grants access where to whom, returning the owner's ID and
+    /// the path relative to their home directory along with the grantee.
+    fn grant_target(
+        &self,
+        web_path: &str,
+        username: Option<&str>,
+        group: Option<&str>,
+        state: &AppState,
+    ) -> anyhow::Result<(i32, String, Principal)> {
+        let ctx = self.context(state)?;
+        let mut db = state.db.get().context("obtain database connection")?;
+        let location = ctx.locate(web_path, &mut db)?;
+        anyhow::ensure!(
+            location.owner == Some(ctx.user_id),
+            "only the owner of {web_path:?} can grant access to it",
+        );
+        let principal = match (username, group) {
+            (Some(username), None) => {
+                let user_id = crate::user::find_id(username, &mut db)?;
+                anyhow::ensure!(user_id != ctx.user_id, "cannot grant access to yourself");
+                Principal::User(user_id)
+            }
+            (None, Some(group)) => Principal::Group(crate::group::find_id(group, &mut db)?),
+            _ => anyhow::bail!("specify either a username or a group"),
+        };
+        Ok((
+            ctx.user_id,
+            crate::safe_path::to_web_path(&location.relative),
+            principal,
+        ))
+    }
+
     pub async fn execute(
         &mut self,
         req: Request,
         state: &Data<AppState>,
     ) -> anyhow::Result<Response> {
+        // Accounts can be disabled or deleted while their sessions are open
+        if let Some(user_id) = self.user_id {
+            let mut db = state.db.get().context("obtain database connection")?;
+            if let Err(err) = crate::user::find_active_role(user_id, &mut db) {
+                log::info!("User ID {user_id} logged out: {err:#}");
+                self.user_id = None;
+                return Err(err.context("logged out"));
+            }
+        }
+
         match req {
             Request::LoginPwd { username, password } => {
                 anyhow::ensure!(self.user_id.is_none(), "already logged in");
 
                 let mut db = state.db.get().context("obtain database connection")?;
                 let user_id = crate::user::login(&username, &password, &mut db)?;
+                // Accounts from before home directories existed need one too
+                crate::home::create_home(user_id).await?;
 
                 log::info!("User {username:?} logged in");
                 self.user_id = Some(user_id);
                 Ok(Response::Empty {})
             }
-            Request::RegisterPwd { username, password } => {
+            Request::RegisterPwd {
+                username,
+                password,
+                invite,
+            } => {
                 anyhow::ensure!(self.user_id.is_none(), "already logged in");
 
                 let mut db = state.db.get().context("obtain database connection")?;
-                let user_id = crate::user::register(&username, &password, &mut db)?;
+                let user_id = crate::user::register(
+                    &username,
+                    &password,
+                    invite.as_deref(),
+                    &state.settings,
+                    &mut db,
+                )?;
+                crate::home::create_home(user_id).await?;
 
                 log::info!("User {username:?} registered and logged in");
                 self.user_id = Some(user_id);
@@ -67,27 +293,373 @@ impl Session {
                 self.user_id = None;
                 Ok(Response::Empty {})
             }
+            Request::ChangePassword {
+                old_password,
+                new_password,
+            } => {
+                let user_id = self.user_id.context("not logged in yet")?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::user::change_password(
+                    user_id,
+                    &old_password,
+                    &new_password,
+                    &state.settings.password_policy,
+                    &mut db,
+                )?;
+                log::info!("User ID {user_id} changed their password");
+                Ok(Response::Empty {})
+            }
             Request::ListDir { path } => {
-                anyhow::ensure!(self.user_id.is_some(), "not logged in yet");
+                let ctx = self.context(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
                 Ok(Response::DirList {
-                    entries: crate::listdir::list_dir(&path).await?,
+                    entries: crate::listdir::list_dir(&ctx, &path, &mut db).await?,
                 })
             }
-            Request::Download { path } => {
-                anyhow::ensure!(self.user_id.is_some(), "not logged in yet");
+            Request::Download { path, format } => {
+                let ctx = self.context(state)?;
                 Ok(Response::DownloadLink {
-                    uuid: crate::api::download::gen_download_uuid(&path, state).await?,
+                    uuid: crate::api::download::gen_download_uuid(
+                        &ctx,
+                        &path,
+                        format,
+                        &ShareOptions::default(),
+                        state,
+                    )
+                    .await?,
                 })
             }
-            Request::Upload { path, size } => {
-                anyhow::ensure!(self.user_id.is_some(), "not logged in yet");
+            Request::DownloadSelection { paths, format } => {
+                let ctx = self.context(state)?;
                 Ok(Response::DownloadLink {
-                    uuid: crate::api::upload::gen_upload_uuid(&path, size, state).await?,
+                    uuid: crate::api::download::gen_selection_uuid(
+                        &ctx,
+                        &paths,
+                        format,
+                        &ShareOptions::default(),
+                        state,
+                    )
+                    .await?,
                 })
             }
+            Request::CreateShare {
+                path,
+                format,
+                password,
+                expires_in,
+                max_downloads,
+            } => {
+                let ctx = self.context(state)?;
+                let mut options = ShareOptions::default();
+                if let Some(password) = &password {
+                    anyhow::ensure!(!password.is_empty(), "password must not be empty");
+                    options.password = Some(password);
+                }
+                if let Some(expires_in) = expires_in {
+                    anyhow::ensure!(
+                        expires_in > 0 && expires_in <= MAX_LIFETIME_SECS as u64,
+                        "expiry must be between 1 and {MAX_LIFETIME_SECS} seconds",
+                    );
+                    options.lifetime_secs = expires_in as i64;
+                }
+                if let Some(max_downloads) = max_downloads {
+                    anyhow::ensure!(max_downloads > 0, "download limit must be positive");
+                    options.max_downloads =
+                        Some(i32::try_from(max_downloads).context("download limit too large")?);
+                }
+                Ok(Response::DownloadLink {
+                    uuid: crate::api::download::gen_download_uuid(
+                        &ctx, &path, format, &options, state,
+                    )
+                    .await?,
+                })
+            }
+            Request::Upload {
+                path,
+                size,
+                checksum,
+            } => {
+                let ctx = self.context(state)?;
+                Ok(Response::DownloadLink {
+                    uuid: crate::api::upload::gen_upload_uuid(
+                        &ctx,
+                        &path,
+                        size,
+                        checksum.as_ref(),
+                        state,
+                    )
+                    .await?,
+                })
+            }
+            Request::ListLinks {} => {
+                let ctx = self.context(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                Ok(Response::LinkList {
+                    links: crate::links::list(&ctx, &mut db)?,
+                })
+            }
+            Request::RevokeLink { uuid } => {
+                let user_id = self.user_id.context("not logged in yet")?;
+                let uuid = uuid::Uuid::parse_str(&uuid).context("parse UUID")?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                let upload_info = crate::links::revoke(user_id, &uuid, &mut db)?;
+                log::info!("User ID {user_id} revoked link {uuid}");
+
+                // Nobody can finish the upload any more, including the clients
+                // still connected to it
+                if upload_info.is_some() {
+                    state.uploads.evict(&uuid).await;
+                }
+                if let Some(upload_info) = upload_info.filter(|info| info.started && !info.finished)
+                {
+                    let staged = crate::api::upload::staging_file(&upload_info.uuid);
+                    if let Err(err) = async_std::fs::remove_file(&staged).await {
+                        log::warn!("Failed to remove unfinished upload {staged:?}: {err}");
+                    }
+                }
+                Ok(Response::Empty {})
+            }
             Request::CreateDir { path } => {
+                let ctx = self.context(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::file_ops::create_dir(&ctx, &path, &mut db).await?;
+                Ok(Response::Empty {})
+            }
+            Request::Rename {
+                path,
+                name,
+                overwrite,
+            } => {
+                let ctx = self.context(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::file_ops::rename(&ctx, &path, &name, overwrite, &mut db).await?;
+                Ok(Response::Empty {})
+            }
+            Request::Move {
+                from,
+                to,
+                overwrite,
+            } => {
+                let ctx = self.context(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::file_ops::move_entry(&ctx, &from, &to, overwrite, &mut db).await?;
+                Ok(Response::Empty {})
+            }
+            Request::Delete {
+                path,
+                recursive,
+                permanent,
+            } => {
+                let ctx = self.context(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                if permanent {
+                    Ok(Response::Deleted {
+                        failed: crate::file_ops::delete(&ctx, &path, recursive, &mut db).await?,
+                    })
+                } else {
+                    crate::trash::move_to_trash(&ctx, &path, recursive, &mut db).await?;
+                    Ok(Response::Deleted { failed: Vec::new() })
+                }
+            }
+            Request::ListTrash {} => {
+                let user_id = self.user_id.context("not logged in yet")?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                Ok(Response::TrashList {
+                    entries: crate::trash::list(user_id, &mut db)?,
+                })
+            }
+            Request::RestoreTrash { id, path } => {
+                let ctx = self.context(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::trash::restore(&ctx, id, path.as_deref(), &mut db).await?;
+                Ok(Response::Empty {})
+            }
+            Request::PurgeTrash { id } => {
+                let user_id = self.user_id.context("not logged in yet")?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                Ok(Response::Deleted {
+                    failed: crate::trash::purge(user_id, id, &mut db).await?,
+                })
+            }
+            Request::Grant {
+                path,
+                username,
+                group,
+                permissions,
+            } => {
+                let (owner_id, relative, principal) =
+                    self.grant_target(&path, username.as_deref(), group.as_deref(), state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::acl::grant(
+                    owner_id,
+                    &relative,
+                    principal,
+                    crate::acl::Permissions::from_list(&permissions),
+                    &mut db,
+                )?;
+                log::info!(
+                    "User ID {owner_id} granted {permissions:?} at {relative:?} to {principal:?}"
+                );
+                Ok(Response::Empty {})
+            }
+            Request::Revoke {
+                path,
+                username,
+                group,
+            } => {
+                let (owner_id, relative, principal) =
+                    self.grant_target(&path, username.as_deref(), group.as_deref(), state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::acl::revoke(owner_id, &relative, principal, &mut db)?;
+                log::info!("User ID {owner_id} revoked access at {relative:?} from {principal:?}");
+                Ok(Response::Empty {})
+            }
+            Request::ListGrants {} => {
+                let user_id = self.user_id.context("not logged in yet")?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                Ok(Response::GrantList {
+                    grants: crate::acl::list_grants(user_id, &mut db)?,
+                })
+            }
+            Request::ListShared {} => {
+                let user_id = self.user_id.context("not logged in yet")?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                Ok(Response::SharedList {
+                    shared: crate::acl::list_shared(user_id, &mut db)?,
+                })
+            }
+            Request::ListGroups {} => {
                 anyhow::ensure!(self.user_id.is_some(), "not logged in yet");
-                crate::file_ops::create_dir(&path).await?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                Ok(Response::GroupList {
+                    groups: crate::group::list(&mut db)?,
+                })
+            }
+            Request::CreateGroup { name } => {
+                self.ensure_admin(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::group::create(&name, &mut db)?;
+                log::info!("Group {name:?} created");
+                Ok(Response::Empty {})
+            }
+            Request::DeleteGroup { name } => {
+                self.ensure_admin(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::group::delete(&name, &mut db)?;
+                log::info!("Group {name:?} deleted");
+                Ok(Response::Empty {})
+            }
+            Request::AddGroupMember { group, username } => {
+                self.ensure_admin(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                let group_id = crate::group::find_id(&group, &mut db)?;
+                let user_id = crate::user::find_id(&username, &mut db)?;
+                crate::group::add_member(group_id, user_id, &mut db)?;
+                Ok(Response::Empty {})
+            }
+            Request::RemoveGroupMember { group, username } => {
+                self.ensure_admin(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                let group_id = crate::group::find_id(&group, &mut db)?;
+                let user_id = crate::user::find_id(&username, &mut db)?;
+                crate::group::remove_member(group_id, user_id, &mut db)?;
+                Ok(Response::Empty {})
+            }
+            Request::CreateInvite {
+                max_uses,
+                expires_in,
+            } => {
+                let user_id = self.user_id.context("not logged in yet")?;
+                let max_uses = max_uses.unwrap_or(1);
+                anyhow::ensure!(max_uses > 0, "use limit must be positive");
+                let max_uses = i32::try_from(max_uses).context("use limit too large")?;
+                let lifetime_secs = match expires_in {
+                    Some(expires_in) => {
+                        anyhow::ensure!(
+                            expires_in > 0 && expires_in <= crate::invite::MAX_LIFETIME_SECS as u64,
+                            "expiry must be between 1 and {} seconds",
+                            crate::invite::MAX_LIFETIME_SECS,
+                        );
+                        expires_in as i64
+                    }
+                    None => crate::invite::LIFETIME_SECS,
+                };
+                let mut db = state.db.get().context("obtain database connection")?;
+                let code = crate::invite::create(user_id, max_uses, lifetime_secs, &mut db)?;
+                log::info!("User ID {user_id} created an invite code for {max_uses} uses");
+                Ok(Response::Invite { code })
+            }
+            Request::ListInvites {} => {
+                // Administrators see everybody's invite codes
+                let user_id = self.user_id.context("not logged in yet")?;
+                let owner = (!self.is_admin(state)?).then_some(user_id);
+                let mut db = state.db.get().context("obtain database connection")?;
+                Ok(Response::InviteList {
+                    invites: crate::invite::list(owner, &mut db)?,
+                })
+            }
+            Request::RevokeInvite { code } => {
+                // Administrators can revoke anybody's invite codes
+                let user_id = self.user_id.context("not logged in yet")?;
+                let owner = (!self.is_admin(state)?).then_some(user_id);
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::invite::revoke(&code, owner, &mut db)?;
+                Ok(Response::Empty {})
+            }
+            Request::ListUsers {} => {
+                self.ensure_admin(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                Ok(Response::UserList {
+                    users: crate::user::list(&mut db)?,
+                })
+            }
+            Request::DisableUser { username } => {
+                let user_id = self.admin_target(&username, state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::user::set_disabled(user_id, true, &mut db)?;
+                log::info!("User {username:?} disabled");
+                Ok(Response::Empty {})
+            }
+            Request::EnableUser { username } => {
+                let user_id = self.admin_target(&username, state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::user::set_disabled(user_id, false, &mut db)?;
+                log::info!("User {username:?} enabled");
+                Ok(Response::Empty {})
+            }
+            Request::SetRole { username, role } => {
+                self.ensure_admin(state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                let user_id = crate::user::find_id(&username, &mut db)?;
+                crate::user::set_role(user_id, role, &mut db)?;
+                log::info!("User {username:?} is now {}", role.as_str());
+                Ok(Response::Empty {})
+            }
+            Request::ResetPassword { username, password } => {
+                let user_id = self.admin_target(&username, state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                crate::user::set_password(
+                    user_id,
+                    &password,
+                    &state.settings.password_policy,
+                    &mut db,
+                )?;
+                log::info!("Password of user {username:?} reset");
+                Ok(Response::Empty {})
+            }
+            Request::DeleteUser {
+                username,
+                files,
+                transfer_to,
+            } => {
+                let user_id = self.admin_target(&username, state)?;
+                let mut db = state.db.get().context("obtain database connection")?;
+                let transfer_to = match &transfer_to {
+                    Some(receiver) => crate::user::find_id(receiver, &mut db)?,
+                    None => self.user_id.unwrap(),
+                };
+                crate::admin::delete_user(user_id, files, transfer_to, &mut db).await?;
+                log::info!("User {username:?} deleted, files: {files:?}");
                 Ok(Response::Empty {})
             }
         }
@@ -116,11 +688,18 @@ impl From<anyhow::Result<Response>> for JsonResponse {
     }
 }
 
-async fn worker(mut ws_session: WsSession, mut msg_stream: MessageStream, state: Data<AppState>) {
+/// Maximum size of a control request, however it was fragmented
+const MAX_REQUEST_SIZE: usize = 64 << 10;
+
+async fn worker(
+    mut ws_session: WsSession,
+    mut msg_stream: AggregatedMessageStream,
+    state: Data<AppState>,
+) {
     let mut session = Session::new();
-    while let Some(msg) = msg_stream.next().await {
+    while let Some(msg) = msg_stream.recv().await {
         match msg {
-            Ok(Message::Text(text)) => {
+            Ok(AggregatedMessage::Text(text)) => {
                 let result = serde_json::from_str(text.as_ref()).context("parse JSON");
                 let result = match result {
                     Ok(req) => session.execute(req, &state).await,
@@ -136,7 +715,8 @@ async fn worker(mut ws_session: WsSession, mut msg_stream: MessageStream, state:
                     log::error!("Failed to send response to client: {err:#}");
                 }
             }
-            Ok(Message::Pong(_)) => (),
+            Ok(AggregatedMessage::Ping(_) | AggregatedMessage::Pong(_)) => (),
+            Ok(AggregatedMessage::Close(_)) => break,
             Ok(other) => {
                 log::debug!("Ignoring unknown WebSocket message: {other:?}");
             }
@@ -155,7 +735,12 @@ pub async fn websocket(
     stream: actix_web::web::Payload,
     state: Data<AppState>,
 ) -> actix_web::Result<HttpResponse> {
-    let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    // Directory listings compress well, unlike the uploads on their own socket
+    let (res, session, msg_stream) = actix_ws::Config::new().deflate(true).handle(&req, stream)?;
+    let msg_stream = msg_stream
+        .heartbeat(HEARTBEAT_INTERVAL, CLIENT_TIMEOUT)
+        .aggregate_continuations()
+        .max_message_size(MAX_REQUEST_SIZE);
     actix_web::rt::spawn(worker(session, msg_stream, state));
     Ok(res)
 }
@@ -7,3 +7,59 @@ pub fn connect() -> DbPool {
         .build(manager)
         .expect("failed to connect to config/db.db")
 }
+
+/// Current wall-clock time as stored in the database (seconds since the Unix
+/// epoch).
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("system clock is before the Unix epoch")
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+const MIGRATIONS: [&str; 12] = [
+    include_str!("../migrations/0_create_schema/up.sql"),
+    include_str!("../migrations/1_create_trash/up.sql"),
+    include_str!("../migrations/2_create_links/up.sql"),
+    include_str!("../migrations/3_add_share_options/up.sql"),
+    include_str!("../migrations/4_add_archive_links/up.sql"),
+    include_str!("../migrations/5_add_selection_links/up.sql"),
+    include_str!("../migrations/6_add_upload_checksum/up.sql"),
+    include_str!("../migrations/7_add_upload_ranges/up.sql"),
+    include_str!("../migrations/8_create_acl/up.sql"),
+    include_str!("../migrations/9_create_groups/up.sql"),
+    include_str!("../migrations/10_add_user_roles/up.sql"),
+    include_str!("../migrations/11_create_invites/up.sql"),
+];
+
+#[cfg(test)]
+fn migrate(db: &mut SqliteConnection) {
+    use diesel::connection::SimpleConnection;
+
+    for migration in MIGRATIONS {
+        db.batch_execute(migration).expect("apply migration");
+    }
+}
+
+/// Opens an empty in-memory database with every migration applied.
+#[cfg(test)]
+pub fn in_memory() -> SqliteConnection {
+    use diesel::Connection;
+
+    let mut db = SqliteConnection::establish(":memory:").expect("open in-memory database");
+    migrate(&mut db);
+    db
+}
+
+/// Like [`in_memory`], but as a pool for code that takes the application
+/// state. Its single connection keeps the database alive.
+#[cfg(test)]
+pub fn in_memory_pool() -> DbPool {
+    let pool = r2d2::Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::<SqliteConnection>::new(":memory:"))
+        .expect("open in-memory database");
+    migrate(&mut pool.get().expect("obtain database connection"));
+    pool
+}
@@ -1,8 +1,269 @@
-use crate::safe_path::normalize_web_path_as_file;
+use std::path::{Path, PathBuf};
 
-pub async fn create_dir(web_path: &str) -> anyhow::Result<()> {
-    let normalized = normalize_web_path_as_file(web_path)?;
+use anyhow::Context;
+use diesel::SqliteConnection;
+use futures_util::StreamExt;
+use serde::Serialize;
+
+use crate::{
+    acl::Permission,
+    home::{Location, UserContext},
+    safe_path::{normalize_web_path, to_web_path},
+};
+
+pub async fn create_dir(
+    ctx: &UserContext,
+    web_path: &str,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let normalized = ctx.authorize(web_path, Permission::Upload, db)?;
     async_std::fs::create_dir(normalized)
         .await
         .map_err(Into::into)
 }
+
+/// Works out where an entry that is about to be moved, replaced or deleted
+/// is, if the user may do that.
+///
+/// Directories that access was granted to, and those containing them, are
+/// left to their owner.
+pub fn locate_removable(
+    ctx: &UserContext,
+    web_path: &str,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Location> {
+    let location = ctx.locate_authorized(web_path, Permission::Modify, db)?;
+    ensure_removable(ctx, &location, web_path, db)?;
+    Ok(location)
+}
+
+fn ensure_removable(
+    ctx: &UserContext,
+    location: &Location,
+    web_path: &str,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !location.is_root(),
+        "the root directory cannot be moved, replaced or deleted",
+    );
+    if let Some(owner) = location.owner.filter(|owner| *owner != ctx.user_id) {
+        anyhow::ensure!(
+            crate::acl::roots_within(owner, &location.relative, db)?.is_empty(),
+            "only the owner can move, replace or delete {web_path:?}, because access was granted to it",
+        );
+    }
+    Ok(())
+}
+
+/// Makes what was granted on an entry follow it to where it was moved, as
+/// long as it stays in the same home directory.
+fn move_grants(src: &Location, dest: &Location, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    match (src.owner, dest.owner) {
+        (Some(src_owner), Some(dest_owner)) if src_owner == dest_owner => {
+            crate::acl::move_grants(src_owner, &src.relative, &dest.relative, db)
+        }
+        (src_owner, dest_owner) => {
+            // Grants mean nothing in another home directory or the shared area
+            if let Some(src_owner) = src_owner {
+                crate::acl::delete_grants(src_owner, &src.relative, db)?;
+            }
+            if let Some(dest_owner) = dest_owner {
+                crate::acl::delete_grants(dest_owner, &dest.relative, db)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Renames an entry without changing its parent directory.
+pub async fn rename(
+    ctx: &UserContext,
+    web_path: &str,
+    new_name: &str,
+    overwrite: bool,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let segment = normalize_web_path(new_name)?;
+    anyhow::ensure!(
+        segment.components().count() == 1 && segment.as_os_str() == new_name.trim(),
+        "{new_name:?} is not a valid file name",
+    );
+
+    let src = locate_removable(ctx, web_path, db)?;
+    let dest = Location {
+        owner: src.owner,
+        relative: src.relative.with_file_name(segment),
+    };
+    ensure_removable(ctx, &dest, new_name, db)?;
+    move_normalized(&src.path(), &dest.path(), overwrite).await?;
+    move_grants(&src, &dest, db)
+}
+
+/// Moves a file or directory to another path, possibly across directories.
+///
+/// With `overwrite` set, an existing file at the destination is replaced.
+/// Existing directories are never replaced. Only the owner may move an entry
+/// out of their home directory.
+pub async fn move_entry(
+    ctx: &UserContext,
+    from: &str,
+    to: &str,
+    overwrite: bool,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let src = locate_removable(ctx, from, db)?;
+    let dest = locate_removable(ctx, to, db)?;
+    anyhow::ensure!(
+        src.owner == dest.owner || src.owner.is_none_or(|owner| owner == ctx.user_id),
+        "only the owner can move {from:?} out of their home directory",
+    );
+    move_normalized(&src.path(), &dest.path(), overwrite).await?;
+    move_grants(&src, &dest, db)
+}
+
+async fn move_normalized(src: &Path, dest: &Path, overwrite: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(src != dest, "source and destination are the same");
+
+    let src_metadata = async_std::fs::symlink_metadata(src)
+        .await
+        .context("source does not exist")?;
+    if src_metadata.is_dir() {
+        anyhow::ensure!(
+            !dest.starts_with(src),
+            "cannot move a directory into itself",
+        );
+    }
+
+    let dest_parent = dest.parent().unwrap();
+    anyhow::ensure!(
+        async_std::path::Path::new(dest_parent).is_dir().await,
+        "destination directory does not exist",
+    );
+
+    if let Ok(dest_metadata) = async_std::fs::symlink_metadata(dest).await {
+        anyhow::ensure!(overwrite, "destination already exists");
+        anyhow::ensure!(
+            !dest_metadata.is_dir(),
+            "destination is a directory and cannot be overwritten",
+        );
+        anyhow::ensure!(
+            !src_metadata.is_dir(),
+            "cannot overwrite a file with a directory",
+        );
+    }
+
+    log::debug!("Moving {src:?} to {dest:?}");
+    async_std::fs::rename(src, dest)
+        .await
+        .context("rename on file system")
+}
+
+#[derive(Serialize)]
+pub struct DeleteFailure {
+    pub path: String,
+    pub err: String,
+}
+
+/// Deletes a file or an empty directory, or with `recursive` set, a whole
+/// directory tree.
+///
+/// A recursive delete carries on past entries that cannot be removed and
+/// returns them instead of failing as a whole.
+pub async fn delete(
+    ctx: &UserContext,
+    web_path: &str,
+    recursive: bool,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Vec<DeleteFailure>> {
+    let relative = normalize_web_path(web_path)?;
+    let location = locate_removable(ctx, web_path, db)?;
+    let normalized = location.path();
+
+    let metadata = async_std::fs::symlink_metadata(&normalized)
+        .await
+        .context("the path specified does not exist")?;
+    if !metadata.is_dir() {
+        async_std::fs::remove_file(&normalized)
+            .await
+            .context("remove file")?;
+        delete_stale_grants(&location, db).await?;
+        return Ok(Vec::new());
+    }
+    if !recursive {
+        async_std::fs::remove_dir(&normalized)
+            .await
+            .context("remove directory (use recursive mode for non-empty directories)")?;
+        delete_stale_grants(&location, db).await?;
+        return Ok(Vec::new());
+    }
+
+    // Walk the tree iteratively, removing each directory after its children
+    let mut failed = Vec::new();
+    let mut stack = vec![(normalized, relative, false)];
+    while let Some((path, relative, visited)) = stack.pop() {
+        let web_path = to_web_path(&relative);
+        let result = if visited {
+            async_std::fs::remove_dir(&path)
+                .await
+                .context("remove directory")
+        } else {
+            match async_std::fs::symlink_metadata(&path).await {
+                Ok(metadata) if metadata.is_dir() => {
+                    stack.push((path.clone(), relative.clone(), true));
+                    push_children(&path, &relative, &mut stack).await
+                }
+                Ok(_) => async_std::fs::remove_file(&path)
+                    .await
+                    .context("remove file"),
+                Err(err) => Err(err).context("read metadata"),
+            }
+        };
+        if let Err(err) = result {
+            log::debug!("Failed to delete {path:?}: {err:#}");
+            failed.push(DeleteFailure {
+                path: web_path,
+                err: format!("{err:#}"),
+            });
+        }
+    }
+    delete_stale_grants(&location, db).await?;
+    Ok(failed)
+}
+
+/// Deletes what was granted on a deleted entry and on whatever in it could be
+/// deleted.
+async fn delete_stale_grants(location: &Location, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    let owner = match location.owner {
+        Some(owner) => owner,
+        None => return Ok(()),
+    };
+    for root in crate::acl::roots_within(owner, &location.relative, db)? {
+        let path = Location {
+            owner: Some(owner),
+            relative: root.clone(),
+        }
+        .path();
+        if async_std::fs::symlink_metadata(&path).await.is_err() {
+            crate::acl::delete_grants(owner, &root, db)?;
+        }
+    }
+    Ok(())
+}
+
+async fn push_children(
+    dir: &Path,
+    relative: &Path,
+    stack: &mut Vec<(PathBuf, PathBuf, bool)>,
+) -> anyhow::Result<()> {
+    let mut readdir = async_std::fs::read_dir(dir).await.context("opendir")?;
+    while let Some(entry) = readdir.next().await {
+        let entry = entry.context("readdir")?;
+        stack.push((
+            dir.join(entry.file_name()),
+            relative.join(entry.file_name()),
+            false,
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use actix_web::web::Data;
+use anyhow::Context;
+use diesel::SqliteConnection;
+
+use crate::state::AppState;
+
+const INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Removes expired download and upload links, deleting the staged files of
+/// unfinished uploads along with them.
+async fn collect_links(db: &mut SqliteConnection) -> anyhow::Result<()> {
+    let num_downloads = crate::links::delete_expired_downloads(db)?;
+    let uploads = crate::links::delete_expired_uploads(db)?;
+
+    let mut num_files = 0;
+    for upload_info in uploads.iter().filter(|info| info.started && !info.finished) {
+        let staged = crate::api::upload::staging_file(&upload_info.uuid);
+        match async_std::fs::remove_file(&staged).await {
+            Ok(()) => {
+                log::debug!("GC: removed unfinished upload {staged:?}");
+                num_files += 1;
+            }
+            Err(err) => log::warn!("GC: failed to remove unfinished upload {staged:?}: {err}"),
+        }
+    }
+
+    if num_downloads > 0 || !uploads.is_empty() {
+        log::info!(
+            "GC: removed {num_downloads} expired download links, {} expired upload links \
+            and {num_files} unfinished uploads",
+            uploads.len(),
+        );
+    }
+    Ok(())
+}
+
+async fn collect(state: &AppState) -> anyhow::Result<()> {
+    let mut db = state.db.get().context("obtain database connection")?;
+    collect_links(&mut db)
+        .await
+        .context("remove expired links")?;
+
+    let num_invites = crate::invite::delete_expired(&mut db).context("remove expired invites")?;
+    if num_invites > 0 {
+        log::info!("GC: removed {num_invites} expired invite codes");
+    }
+
+    let num_purged = crate::trash::purge_expired(&mut db)
+        .await
+        .context("purge expired trash items")?;
+    if num_purged > 0 {
+        log::info!("GC: purged {num_purged} expired trash items");
+    }
+    Ok(())
+}
+
+/// Periodically cleans up expired server state.
+pub async fn run(state: Data<AppState>) {
+    let mut interval = actix_web::rt::time::interval(INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = collect(&state).await {
+            log::error!("GC failed: {err:#}");
+        }
+    }
+}
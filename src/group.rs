@@ -0,0 +1,133 @@
+use anyhow::Context;
+use diesel::{Connection, ExpressionMethods, JoinOnDsl, QueryDsl, RunQueryDsl, SqliteConnection};
+use serde::Serialize;
+
+use crate::models::GroupMember;
+
+const NAME_LENGTH_LIMIT: usize = 64;
+
+#[derive(Serialize)]
+pub struct GroupEntry {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+pub fn find_id(name: &str, db: &mut SqliteConnection) -> anyhow::Result<i32> {
+    use crate::schema::groups::dsl;
+
+    let mut records: Vec<i32> = dsl::groups
+        .filter(dsl::name.eq(name))
+        .select(dsl::id)
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    records
+        .pop()
+        .with_context(|| format!("group {name:?} does not exist"))
+}
+
+pub fn find_name(group_id: i32, db: &mut SqliteConnection) -> anyhow::Result<String> {
+    use crate::schema::groups::dsl;
+
+    let mut records: Vec<String> = dsl::groups
+        .filter(dsl::id.eq(group_id))
+        .select(dsl::name)
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    records.pop().context("the group does not exist")
+}
+
+pub fn create(name: &str, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::groups::dsl;
+
+    anyhow::ensure!(
+        !name.trim().is_empty() && name.trim() == name,
+        "{name:?} is not a valid group name",
+    );
+    anyhow::ensure!(
+        name.len() <= NAME_LENGTH_LIMIT,
+        "group names cannot be longer than {NAME_LENGTH_LIMIT} bytes",
+    );
+    anyhow::ensure!(find_id(name, db).is_err(), "group {name:?} already exists");
+    diesel::insert_into(dsl::groups)
+        .values(dsl::name.eq(name))
+        .execute(db)
+        .context("insert into database")?;
+    Ok(())
+}
+
+/// Deletes a group along with its memberships and everything granted to it.
+pub fn delete(name: &str, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::{group_acl, group_members, groups};
+
+    let group_id = find_id(name, db)?;
+    db.transaction(|db| {
+        diesel::delete(group_acl::table.filter(group_acl::group_id.eq(group_id))).execute(db)?;
+        diesel::delete(group_members::table.filter(group_members::group_id.eq(group_id)))
+            .execute(db)?;
+        diesel::delete(groups::table.filter(groups::id.eq(group_id))).execute(db)
+    })
+    .context("delete from database")?;
+    Ok(())
+}
+
+pub fn add_member(group_id: i32, user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    diesel::insert_or_ignore_into(crate::schema::group_members::table)
+        .values(&GroupMember { group_id, user_id })
+        .execute(db)
+        .context("insert into database")?;
+    Ok(())
+}
+
+pub fn remove_member(group_id: i32, user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::group_members::dsl;
+
+    let num_deleted = diesel::delete(
+        dsl::group_members
+            .filter(dsl::group_id.eq(group_id))
+            .filter(dsl::user_id.eq(user_id)),
+    )
+    .execute(db)
+    .context("delete from database")?;
+    anyhow::ensure!(num_deleted > 0, "the user is not a member of the group");
+    Ok(())
+}
+
+/// Removes a user from all groups.
+pub fn remove_from_all(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::group_members::dsl;
+
+    diesel::delete(dsl::group_members.filter(dsl::user_id.eq(user_id)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(())
+}
+
+/// Lists all groups with the names of their members.
+pub fn list(db: &mut SqliteConnection) -> anyhow::Result<Vec<GroupEntry>> {
+    use crate::schema::{group_members, groups, users};
+
+    let groups: Vec<(i32, String)> = groups::table
+        .order(groups::name)
+        .load(db)
+        .context("query database")?;
+    let memberships: Vec<(i32, String)> = group_members::table
+        .inner_join(users::table.on(users::id.eq(group_members::user_id)))
+        .select((group_members::group_id, users::username))
+        .order(users::username)
+        .load(db)
+        .context("query database")?;
+
+    Ok(groups
+        .into_iter()
+        .map(|(id, name)| GroupEntry {
+            name,
+            members: memberships
+                .iter()
+                .filter(|(group_id, _)| *group_id == id)
+                .map(|(_, username)| username.clone())
+                .collect(),
+        })
+        .collect())
+}
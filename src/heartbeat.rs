@@ -0,0 +1,10 @@
+//! How the server notices WebSocket clients that have gone away without
+//! closing their connection.
+
+use std::time::Duration;
+
+/// How often clients are pinged
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a client may stay silent before its connection is dropped
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
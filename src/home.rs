@@ -0,0 +1,375 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use diesel::SqliteConnection;
+
+use crate::{
+    acl::Permission,
+    safe_path::{ensure_legal_segment, normalize_web_path, to_web_path},
+    state::AppState,
+};
+
+/// Where all files were kept before there were home directories.
+const FILES_DIR: &str = "files";
+
+/// Every user's files live under here, in a directory named after their ID.
+const HOMES_DIR: &str = "files/home";
+
+/// Files of the shared area live here.
+const SHARED_DIR: &str = "files/shared";
+
+/// First segment of the web paths that point into the shared area.
+pub const SHARED_PREFIX: &str = "@shared";
+
+/// Starts the first segment of web paths that point into the home directory
+/// of the user named by the rest of the segment.
+///
+/// Such paths never refer to the user's own home directory, so no entry named
+/// like this can be created at its top. Entries that already are, like
+/// `~$report.docx`, are still listed but cannot be reached until renamed on
+/// the server.
+pub const HOME_PREFIX: char = '~';
+
+/// Checks that `name` can be given to an entry at the top of a home
+/// directory, where it must not look like a prefix of other places.
+pub fn ensure_top_level_name(name: &str) -> anyhow::Result<()> {
+    ensure_legal_segment(name)?;
+    anyhow::ensure!(
+        !matches!(name, "" | "." | ".."),
+        "{name:?} is not a valid name",
+    );
+    anyhow::ensure!(
+        !name.starts_with(HOME_PREFIX) && name != SHARED_PREFIX,
+        "{name:?} would be taken for another home directory or the shared area",
+    );
+    Ok(())
+}
+
+fn home_dir(user_id: i32) -> PathBuf {
+    Path::new(HOMES_DIR).join(user_id.to_string())
+}
+
+/// Creates the home directory of a user, if it does not exist yet.
+pub async fn create_home(user_id: i32) -> anyhow::Result<()> {
+    async_std::fs::create_dir_all(home_dir(user_id))
+        .await
+        .context("create home directory")
+}
+
+/// Deletes the home directory of a user along with everything in it.
+pub async fn remove_home(user_id: i32) -> anyhow::Result<()> {
+    match async_std::fs::remove_dir_all(home_dir(user_id)).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(err).context("remove home directory")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Moves the home directory of a user into that of `to_id`, as a directory
+/// called `name`.
+///
+/// Names that cannot be given to such a directory, like those of accounts
+/// from before usernames were checked, are replaced by one made of the ID.
+pub async fn transfer_home(from_id: i32, to_id: i32, name: &str) -> anyhow::Result<()> {
+    let name = match ensure_top_level_name(name) {
+        Ok(()) => name.to_owned(),
+        Err(err) => {
+            let fallback = format!("user-{from_id}");
+            log::warn!("Transferring files into {fallback:?} instead of {name:?}: {err:#}");
+            fallback
+        }
+    };
+    let target = home_dir(to_id).join(&name);
+    anyhow::ensure!(
+        async_std::fs::symlink_metadata(&target).await.is_err(),
+        "{name:?} already exists in the receiving home directory",
+    );
+    create_home(to_id).await?;
+    create_home(from_id).await?;
+    async_std::fs::rename(home_dir(from_id), &target)
+        .await
+        .context("move home directory")
+}
+
+/// Moves files left directly under [`FILES_DIR`] by versions without home
+/// directories into the home directory of the oldest account, which is the
+/// administrator set up first.
+///
+/// Entries named like the directories of homes or the shared area cannot be
+/// told apart from them, and are left alone.
+pub fn migrate_legacy_files(db: &mut SqliteConnection) -> anyhow::Result<()> {
+    let homes = Path::new(HOMES_DIR);
+    let shared = Path::new(SHARED_DIR);
+    let mut legacy = Vec::new();
+    let entries = match std::fs::read_dir(FILES_DIR) {
+        Ok(entries) => entries,
+        // A fresh install, with nothing to migrate
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("list files directory"),
+    };
+    for entry in entries {
+        let path = entry.context("list files directory")?.path();
+        if path != homes && path != shared {
+            legacy.push(path);
+        }
+    }
+    if legacy.is_empty() {
+        return Ok(());
+    }
+
+    let owner = match crate::user::find_first(db)? {
+        Some(owner) => owner,
+        None => {
+            log::warn!(
+                "{} entries in {FILES_DIR:?} predate home directories, and will be moved on the first start after a user registers",
+                legacy.len(),
+            );
+            return Ok(());
+        }
+    };
+    let home = home_dir(owner);
+    std::fs::create_dir_all(&home).context("create home directory")?;
+    for path in legacy {
+        let target = home.join(path.file_name().unwrap());
+        if std::fs::symlink_metadata(&target).is_ok() {
+            log::warn!("Not moving {path:?}, {target:?} already exists");
+            continue;
+        }
+        std::fs::rename(&path, &target).with_context(|| format!("move {path:?}"))?;
+        log::info!("Moved {path:?} from before home directories to {target:?}");
+        if path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(HOME_PREFIX)
+        {
+            log::warn!("{target:?} cannot be reached until renamed, see HOME_PREFIX");
+        }
+    }
+    Ok(())
+}
+
+pub fn create_shared_area() -> anyhow::Result<()> {
+    std::fs::create_dir_all(SHARED_DIR).context("create shared area")
+}
+
+/// Converts a path returned by [`UserContext::authorize`] back to its web
+/// form, as seen by the owner.
+pub fn file_to_web_path(file: &str) -> String {
+    let file = Path::new(file);
+    let relative = if let Ok(relative) = file.strip_prefix(SHARED_DIR) {
+        Path::new(SHARED_PREFIX).join(relative)
+    } else if let Ok(relative) = file.strip_prefix(HOMES_DIR) {
+        // Skip the user ID
+        relative.iter().skip(1).collect()
+    } else {
+        // Stored before there were home directories
+        file.strip_prefix("files").unwrap_or(file).to_owned()
+    };
+    to_web_path(&relative)
+}
+
+/// Where a web path points to.
+pub struct Location {
+    /// Whose home directory it is in, or `None` for the shared area.
+    pub owner: Option<i32>,
+    /// Normalized path relative to the home directory or the shared area.
+    pub relative: PathBuf,
+}
+
+impl Location {
+    /// Works out where a path returned by [`UserContext::authorize`] points
+    /// to, unless it was stored before there were home directories.
+    pub fn of_file(file: &Path) -> Option<Location> {
+        if let Ok(relative) = file.strip_prefix(SHARED_DIR) {
+            return Some(Location {
+                owner: None,
+                relative: relative.to_owned(),
+            });
+        }
+        let mut segments = file.strip_prefix(HOMES_DIR).ok()?.iter();
+        let owner = segments.next()?.to_str()?.parse().ok()?;
+        Some(Location {
+            owner: Some(owner),
+            relative: segments.collect(),
+        })
+    }
+
+    /// Whether `user_id` has `permission` here.
+    ///
+    /// Users may do anything in their own home directory and in the shared
+    /// area. Elsewhere, they need to be granted access by the owner.
+    pub fn permits(
+        &self,
+        user_id: i32,
+        permission: Permission,
+        db: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        match self.owner {
+            Some(owner) if owner != user_id => {
+                let granted = crate::acl::permissions(owner, &self.relative, user_id, db)?;
+                Ok(granted.contains(permission))
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Whether this is the root of a home directory or of the shared area,
+    /// which cannot be moved or deleted.
+    pub fn is_root(&self) -> bool {
+        self.relative.as_os_str().is_empty()
+    }
+
+    pub fn path(&self) -> PathBuf {
+        match self.owner {
+            Some(owner) => home_dir(owner).join(&self.relative),
+            None => Path::new(SHARED_DIR).join(&self.relative),
+        }
+    }
+}
+
+/// Whose files web paths refer to, and what they may do with them.
+///
+/// Web paths are relative to the user's home directory, except for those
+/// starting with [`SHARED_PREFIX`] or [`HOME_PREFIX`].
+#[derive(Clone)]
+pub struct UserContext {
+    pub user_id: i32,
+    shared_area: bool,
+}
+
+impl UserContext {
+    pub fn new(user_id: i32, state: &AppState) -> UserContext {
+        UserContext {
+            user_id,
+            shared_area: state.settings.shared_area,
+        }
+    }
+
+    pub fn has_shared_area(&self) -> bool {
+        self.shared_area
+    }
+
+    pub fn locate(&self, web_path: &str, db: &mut SqliteConnection) -> anyhow::Result<Location> {
+        let relative = normalize_web_path(web_path)?;
+        let mut segments = relative.iter();
+        let first = segments.next().map(|first| first.to_string_lossy());
+        match first.as_deref() {
+            Some(SHARED_PREFIX) => {
+                anyhow::ensure!(self.shared_area, "the shared area is disabled");
+                Ok(Location {
+                    owner: None,
+                    relative: segments.collect(),
+                })
+            }
+            Some(first) if first.starts_with(HOME_PREFIX) => {
+                let name = &first[HOME_PREFIX.len_utf8()..];
+                let owner = crate::user::lookup_id(name, db)?
+                    .with_context(|| format!("no user named {name:?}"))?;
+                Ok(Location {
+                    owner: Some(owner),
+                    relative: segments.collect(),
+                })
+            }
+            _ => Ok(Location {
+                owner: Some(self.user_id),
+                relative,
+            }),
+        }
+    }
+
+    /// Works out where a web path points to, if the user has `permission`
+    /// there.
+    pub fn locate_authorized(
+        &self,
+        web_path: &str,
+        permission: Permission,
+        db: &mut SqliteConnection,
+    ) -> anyhow::Result<Location> {
+        let location = self.locate(web_path, db)?;
+        anyhow::ensure!(
+            location.permits(self.user_id, permission, db)?,
+            "permission denied: no {permission} access to {web_path:?}",
+        );
+        Ok(location)
+    }
+
+    /// Maps a web path to where it is stored on the file system, if the user
+    /// has `permission` there.
+    pub fn authorize(
+        &self,
+        web_path: &str,
+        permission: Permission,
+        db: &mut SqliteConnection,
+    ) -> anyhow::Result<PathBuf> {
+        Ok(self.locate_authorized(web_path, permission, db)?.path())
+    }
+
+    /// Converts a path returned by [`UserContext::authorize`] back to the web
+    /// form it would have been given in.
+    pub fn file_to_web_path(
+        &self,
+        file: &str,
+        db: &mut SqliteConnection,
+    ) -> anyhow::Result<String> {
+        let web_path = file_to_web_path(file);
+        let owner = Location::of_file(Path::new(file)).and_then(|location| location.owner);
+        match owner {
+            Some(owner) if owner != self.user_id => {
+                let owner = crate::user::find_username(owner, db)?;
+                Ok(if web_path.is_empty() {
+                    format!("{HOME_PREFIX}{owner}")
+                } else {
+                    format!("{HOME_PREFIX}{owner}/{web_path}")
+                })
+            }
+            _ => Ok(web_path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use diesel::RunQueryDsl;
+
+    use super::UserContext;
+    use crate::models::NewUser;
+
+    #[test]
+    fn locate_home_prefix() {
+        let mut db = crate::db::in_memory();
+        for username in ["alice", "bob"] {
+            diesel::insert_into(crate::schema::users::table)
+                .values(&NewUser {
+                    username,
+                    hashed_pass: "",
+                    role: "user",
+                })
+                .execute(&mut db)
+                .unwrap();
+        }
+        let alice = crate::user::find_id("alice", &mut db).unwrap();
+        let bob = crate::user::find_id("bob", &mut db).unwrap();
+        let ctx = UserContext {
+            user_id: alice,
+            shared_area: false,
+        };
+
+        let location = ctx.locate("/docs/a.txt", &mut db).unwrap();
+        assert_eq!(location.owner, Some(alice));
+        assert_eq!(location.relative, Path::new("docs/a.txt"));
+
+        let location = ctx.locate("/~bob/docs", &mut db).unwrap();
+        assert_eq!(location.owner, Some(bob));
+        assert_eq!(location.relative, Path::new("docs"));
+        assert!(ctx.locate("/~bob", &mut db).unwrap().is_root());
+
+        // Never falls back to the user's own home directory
+        assert!(ctx.locate("/~$report.docx", &mut db).is_err());
+        assert!(ctx.locate("/~", &mut db).is_err());
+        assert!(ctx.locate("/@shared/a.txt", &mut db).is_err());
+    }
+}
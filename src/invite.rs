@@ -0,0 +1,159 @@
+use anyhow::Context;
+use diesel::{
+    result::{DatabaseErrorKind, Error as DieselError},
+    BoolExpressionMethods, ExpressionMethods, JoinOnDsl, QueryDsl, RunQueryDsl, SqliteConnection,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+
+use crate::models::Invite;
+
+/// How long invite codes stay valid by default.
+pub const LIFETIME_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// The longest lifetime an invite code may be given.
+pub const MAX_LIFETIME_SECS: i64 = 365 * 24 * 60 * 60;
+
+const CODE_LENGTH: usize = 16;
+
+/// An invite code as shown to whoever may see it.
+#[derive(Serialize)]
+pub struct InviteEntry {
+    pub code: String,
+    pub created_by: String,
+    pub max_uses: i32,
+    pub num_uses: i32,
+    pub expires: i64,
+}
+
+/// Mints an invite code that can be used `max_uses` times.
+pub fn create(
+    user_id: i32,
+    max_uses: i32,
+    lifetime_secs: i64,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<String> {
+    for _ in 0..20 {
+        let code: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(CODE_LENGTH)
+            .map(char::from)
+            .collect();
+        let result = diesel::insert_into(crate::schema::invites::table)
+            .values(&Invite {
+                code: code.clone(),
+                created_by: user_id,
+                max_uses,
+                num_uses: 0,
+                expires: crate::db::now() + lifetime_secs,
+            })
+            .execute(db);
+        match result {
+            Ok(_) => return Ok(code),
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => continue,
+            Err(err) => return Err(err).context("insert into database"),
+        }
+    }
+    anyhow::bail!("failed to allocate an invite code");
+}
+
+/// Counts a use against an unexpired invite code, failing if it has been used
+/// up or its creator has been disabled.
+pub fn consume(code: &str, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::{invites::dsl, users};
+
+    // Check and increment in one statement, so that parallel registrations
+    // cannot exceed the limit
+    let enabled = users::table
+        .filter(users::disabled.eq(false))
+        .select(users::id);
+    let num_updated = diesel::update(
+        dsl::invites
+            .filter(dsl::code.eq(code))
+            .filter(dsl::expires.gt(crate::db::now()))
+            .filter(dsl::num_uses.lt(dsl::max_uses))
+            .filter(dsl::created_by.eq_any(enabled)),
+    )
+    .set(dsl::num_uses.eq(dsl::num_uses + 1))
+    .execute(db)
+    .context("update database")?;
+    anyhow::ensure!(num_updated > 0, "the invite code is invalid or has expired");
+    Ok(())
+}
+
+/// Lists unexpired invite codes, either all of them or only those created by
+/// `user_id`.
+pub fn list(user_id: Option<i32>, db: &mut SqliteConnection) -> anyhow::Result<Vec<InviteEntry>> {
+    use crate::schema::{invites, users};
+
+    let mut query = invites::table
+        .inner_join(users::table.on(users::id.eq(invites::created_by)))
+        .filter(invites::expires.gt(crate::db::now()))
+        .select((
+            invites::code,
+            users::username,
+            invites::max_uses,
+            invites::num_uses,
+            invites::expires,
+        ))
+        .order(invites::expires)
+        .into_boxed();
+    if let Some(user_id) = user_id {
+        query = query.filter(invites::created_by.eq(user_id));
+    }
+    let records: Vec<(String, String, i32, i32, i64)> = query.load(db).context("query database")?;
+    Ok(records
+        .into_iter()
+        .map(
+            |(code, created_by, max_uses, num_uses, expires)| InviteEntry {
+                code,
+                created_by,
+                max_uses,
+                num_uses,
+                expires,
+            },
+        )
+        .collect())
+}
+
+/// Deletes an invite code, as long as it was created by `user_id` unless that
+/// is `None`.
+pub fn revoke(code: &str, user_id: Option<i32>, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::invites::dsl;
+
+    let mut query = diesel::delete(dsl::invites)
+        .filter(dsl::code.eq(code))
+        .into_boxed();
+    if let Some(user_id) = user_id {
+        query = query.filter(dsl::created_by.eq(user_id));
+    }
+    let num_deleted = query.execute(db).context("delete from database")?;
+    anyhow::ensure!(num_deleted > 0, "the invite code does not exist");
+    Ok(())
+}
+
+/// Deletes all invite codes that a user created.
+pub fn delete_all(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::invites::dsl;
+
+    diesel::delete(dsl::invites.filter(dsl::created_by.eq(user_id)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(())
+}
+
+/// Deletes all expired or used up invite codes, returning how many were
+/// deleted.
+pub fn delete_expired(db: &mut SqliteConnection) -> anyhow::Result<usize> {
+    use crate::schema::invites::dsl;
+
+    diesel::delete(
+        dsl::invites.filter(
+            dsl::expires
+                .le(crate::db::now())
+                .or(dsl::num_uses.ge(dsl::max_uses)),
+        ),
+    )
+    .execute(db)
+    .context("delete from database")
+}
@@ -0,0 +1,427 @@
+use std::path::Path;
+
+use anyhow::Context;
+use diesel::{
+    result::{DatabaseErrorKind, Error as DieselError},
+    BoolExpressionMethods, ExpressionMethods, NullableExpressionMethods, QueryDsl, RunQueryDsl,
+    SqliteConnection,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    acl::Permission,
+    archive::ArchiveFormat,
+    checksum::Checksum,
+    home::{Location, UserContext},
+    models::{DownloadInfo, UploadInfo},
+    range_set::RangeSet,
+};
+
+/// How long download and upload links stay valid by default.
+pub const LIFETIME_SECS: i64 = 24 * 60 * 60;
+
+/// The longest lifetime a share link may be given.
+pub const MAX_LIFETIME_SECS: i64 = 365 * 24 * 60 * 60;
+
+/// Restrictions on who may use a download link, and how often.
+pub struct ShareOptions<'a> {
+    pub password: Option<&'a str>,
+    pub lifetime_secs: i64,
+    pub max_downloads: Option<i32>,
+}
+
+impl Default for ShareOptions<'_> {
+    fn default() -> Self {
+        ShareOptions {
+            password: None,
+            lifetime_secs: LIFETIME_SECS,
+            max_downloads: None,
+        }
+    }
+}
+
+/// A link as shown to the user who created it.
+#[derive(Serialize)]
+#[serde(tag = "kind")]
+pub enum LinkEntry {
+    Download {
+        uuid: String,
+        path: String,
+        expires: i64,
+        password: bool,
+        max_downloads: Option<i32>,
+        num_downloads: i32,
+        archive: Option<String>,
+        selection: Option<Vec<String>>,
+    },
+    Upload {
+        uuid: String,
+        path: String,
+        expires: i64,
+        size: i64,
+        finished: bool,
+    },
+}
+
+/// Allocates a random UUID and stores a link under it with `insert`, retrying
+/// if the UUID is already taken.
+fn allocate(
+    mut insert: impl FnMut(String) -> Result<usize, DieselError>,
+) -> anyhow::Result<String> {
+    for _ in 0..20 {
+        let uuid = uuid::Builder::from_random_bytes(rand::random())
+            .into_uuid()
+            .to_string();
+        match insert(uuid.clone()) {
+            Ok(_) => return Ok(uuid),
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => continue,
+            Err(err) => return Err(err).context("insert into database"),
+        }
+    }
+    anyhow::bail!("failed to allocate a UUID for this link");
+}
+
+/// Creates a download link for `file`, optionally served as an archive of
+/// only the selected `entries`.
+pub fn create_download(
+    user_id: i32,
+    file: &str,
+    archive: Option<ArchiveFormat>,
+    entries: Option<&[String]>,
+    options: &ShareOptions,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<String> {
+    let hashed_pass = options
+        .password
+        .map(|password| crate::user::pwhash_as_str(&crate::user::pwhash(password)).to_owned());
+    let entries = entries.map(|entries| serde_json::to_string(entries).unwrap());
+    allocate(|uuid| {
+        diesel::insert_into(crate::schema::download_links::table)
+            .values(&DownloadInfo {
+                uuid,
+                user_id,
+                file: file.to_owned(),
+                expires: crate::db::now() + options.lifetime_secs,
+                hashed_pass: hashed_pass.clone(),
+                max_downloads: options.max_downloads,
+                num_downloads: 0,
+                archive: archive.map(|format| format.as_str().to_owned()),
+                entries: entries.clone(),
+            })
+            .execute(db)
+    })
+}
+
+pub fn create_upload(
+    user_id: i32,
+    file: &str,
+    size: u64,
+    checksum: Option<&Checksum>,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<String> {
+    let checksum = checksum.map(Checksum::to_stored);
+    allocate(|uuid| {
+        diesel::insert_into(crate::schema::upload_links::table)
+            .values(&UploadInfo {
+                uuid,
+                user_id,
+                file: file.to_owned(),
+                size: size as i64,
+                started: false,
+                finished: false,
+                expires: crate::db::now() + LIFETIME_SECS,
+                checksum: checksum.clone(),
+                received: "[]".to_owned(),
+            })
+            .execute(db)
+    })
+}
+
+/// Whether the creator of a link may still do `permission` with `file`, as
+/// access granted by somebody else can be revoked while the link is alive.
+fn creator_permitted(
+    user_id: i32,
+    file: &str,
+    permission: Permission,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<bool> {
+    match Location::of_file(Path::new(file)) {
+        Some(location) => location.permits(user_id, permission, db),
+        None => Ok(true),
+    }
+}
+
+/// Looks up an unexpired download link with downloads left, whose creator is
+/// enabled and may still share what it points to.
+pub fn find_download(
+    uuid: &Uuid,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Option<DownloadInfo>> {
+    use crate::schema::{download_links::dsl, users};
+
+    let enabled = users::table
+        .filter(users::disabled.eq(false))
+        .select(users::id);
+    let mut records: Vec<DownloadInfo> = dsl::download_links
+        .filter(dsl::uuid.eq(uuid.to_string()))
+        .filter(dsl::expires.gt(crate::db::now()))
+        .filter(
+            dsl::max_downloads
+                .is_null()
+                .or(dsl::num_downloads.lt(dsl::max_downloads.assume_not_null())),
+        )
+        .filter(dsl::user_id.eq_any(enabled))
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    let download_info = match records.pop() {
+        Some(download_info) => download_info,
+        None => return Ok(None),
+    };
+
+    // Selected entries may be shared while their common ancestor is not
+    let files = match &download_info.entries {
+        Some(entries) => serde_json::from_str(entries).context("parse selected entries")?,
+        None => vec![download_info.file.clone()],
+    };
+    for file in &files {
+        if !creator_permitted(download_info.user_id, file, Permission::Share, db)? {
+            return Ok(None);
+        }
+    }
+    Ok(Some(download_info))
+}
+
+/// Counts a download against the link's limit, returning `false` if the
+/// limit has already been reached.
+pub fn count_download(uuid: &Uuid, db: &mut SqliteConnection) -> anyhow::Result<bool> {
+    use crate::schema::download_links::dsl;
+
+    // Check and increment in one statement, so that parallel downloads cannot
+    // exceed the limit
+    let num_updated = diesel::update(
+        dsl::download_links
+            .filter(dsl::uuid.eq(uuid.to_string()))
+            .filter(
+                dsl::max_downloads
+                    .is_null()
+                    .or(dsl::num_downloads.lt(dsl::max_downloads.assume_not_null())),
+            ),
+    )
+    .set(dsl::num_downloads.eq(dsl::num_downloads + 1))
+    .execute(db)
+    .context("update database")?;
+    Ok(num_updated > 0)
+}
+
+/// Looks up an unexpired upload link whose creator is enabled and may still
+/// upload to where it points to.
+pub fn find_upload(uuid: &Uuid, db: &mut SqliteConnection) -> anyhow::Result<Option<UploadInfo>> {
+    use crate::schema::{upload_links::dsl, users};
+
+    let enabled = users::table
+        .filter(users::disabled.eq(false))
+        .select(users::id);
+    let mut records: Vec<UploadInfo> = dsl::upload_links
+        .filter(dsl::uuid.eq(uuid.to_string()))
+        .filter(dsl::expires.gt(crate::db::now()))
+        .filter(dsl::user_id.eq_any(enabled))
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    match records.pop() {
+        Some(upload_info)
+            if creator_permitted(
+                upload_info.user_id,
+                &upload_info.file,
+                Permission::Upload,
+                db,
+            )? =>
+        {
+            Ok(Some(upload_info))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Checks whether an unexpired, unfinished upload link to `file` exists.
+pub fn upload_pending(file: &str, db: &mut SqliteConnection) -> anyhow::Result<bool> {
+    use crate::schema::upload_links::dsl;
+
+    let num_links: i64 = dsl::upload_links
+        .filter(dsl::file.eq(file))
+        .filter(dsl::finished.eq(false))
+        .filter(dsl::expires.gt(crate::db::now()))
+        .count()
+        .get_result(db)
+        .context("query database")?;
+    Ok(num_links > 0)
+}
+
+pub fn set_upload_progress(
+    uuid: &Uuid,
+    started: bool,
+    finished: bool,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    use crate::schema::upload_links::dsl;
+
+    diesel::update(dsl::upload_links.filter(dsl::uuid.eq(uuid.to_string())))
+        .set((dsl::started.eq(started), dsl::finished.eq(finished)))
+        .execute(db)
+        .context("update database")?;
+    Ok(())
+}
+
+/// Deletes an upload link regardless of who created it.
+pub fn delete_upload(uuid: &Uuid, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::upload_links::dsl;
+
+    diesel::delete(dsl::upload_links.filter(dsl::uuid.eq(uuid.to_string())))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(())
+}
+
+/// Records which byte ranges of an upload have safely reached the disk.
+pub fn set_upload_received(
+    uuid: &Uuid,
+    received: &RangeSet,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    use crate::schema::upload_links::dsl;
+
+    diesel::update(dsl::upload_links.filter(dsl::uuid.eq(uuid.to_string())))
+        .set(dsl::received.eq(serde_json::to_string(received).unwrap()))
+        .execute(db)
+        .context("update database")?;
+    Ok(())
+}
+
+/// Deletes all expired download links, returning how many were deleted.
+pub fn delete_expired_downloads(db: &mut SqliteConnection) -> anyhow::Result<usize> {
+    use crate::schema::download_links::dsl;
+
+    diesel::delete(dsl::download_links.filter(dsl::expires.le(crate::db::now())))
+        .execute(db)
+        .context("delete from database")
+}
+
+/// Deletes all expired upload links, returning the deleted links.
+pub fn delete_expired_uploads(db: &mut SqliteConnection) -> anyhow::Result<Vec<UploadInfo>> {
+    use crate::schema::upload_links::dsl;
+
+    let now = crate::db::now();
+    let expired: Vec<UploadInfo> = dsl::upload_links
+        .filter(dsl::expires.le(now))
+        .load(db)
+        .context("query database")?;
+    diesel::delete(dsl::upload_links.filter(dsl::expires.le(now)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(expired)
+}
+
+/// Deletes all links that a user created, returning the upload links.
+pub fn delete_all(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<Vec<UploadInfo>> {
+    use crate::schema::{download_links, upload_links};
+
+    diesel::delete(download_links::table.filter(download_links::user_id.eq(user_id)))
+        .execute(db)
+        .context("delete from database")?;
+    let uploads: Vec<UploadInfo> = upload_links::table
+        .filter(upload_links::user_id.eq(user_id))
+        .load(db)
+        .context("query database")?;
+    diesel::delete(upload_links::table.filter(upload_links::user_id.eq(user_id)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(uploads)
+}
+
+/// Lists the user's unexpired links.
+pub fn list(ctx: &UserContext, db: &mut SqliteConnection) -> anyhow::Result<Vec<LinkEntry>> {
+    use crate::schema::{download_links, upload_links};
+
+    let user_id = ctx.user_id;
+    let now = crate::db::now();
+    let downloads: Vec<DownloadInfo> = download_links::table
+        .filter(download_links::user_id.eq(user_id))
+        .filter(download_links::expires.gt(now))
+        .order(download_links::expires)
+        .load(db)
+        .context("query database")?;
+    let uploads: Vec<UploadInfo> = upload_links::table
+        .filter(upload_links::user_id.eq(user_id))
+        .filter(upload_links::expires.gt(now))
+        .order(upload_links::expires)
+        .load(db)
+        .context("query database")?;
+
+    let mut result = Vec::with_capacity(downloads.len() + uploads.len());
+    for info in downloads {
+        let selection = info
+            .entries
+            .and_then(|entries| serde_json::from_str::<Vec<String>>(&entries).ok())
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|file| ctx.file_to_web_path(file, db))
+                    .collect::<anyhow::Result<_>>()
+            })
+            .transpose()?;
+        result.push(LinkEntry::Download {
+            path: ctx.file_to_web_path(&info.file, db)?,
+            uuid: info.uuid,
+            expires: info.expires,
+            password: info.hashed_pass.is_some(),
+            max_downloads: info.max_downloads,
+            num_downloads: info.num_downloads,
+            archive: info.archive,
+            selection,
+        });
+    }
+    for info in uploads {
+        result.push(LinkEntry::Upload {
+            path: ctx.file_to_web_path(&info.file, db)?,
+            uuid: info.uuid,
+            expires: info.expires,
+            size: info.size,
+            finished: info.finished,
+        });
+    }
+    Ok(result)
+}
+
+/// Deletes one of the user's links, returning the upload link if it was one.
+pub fn revoke(
+    user_id: i32,
+    uuid: &Uuid,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Option<UploadInfo>> {
+    use crate::schema::{download_links, upload_links};
+
+    let num_deleted = diesel::delete(
+        download_links::table
+            .filter(download_links::uuid.eq(uuid.to_string()))
+            .filter(download_links::user_id.eq(user_id)),
+    )
+    .execute(db)
+    .context("delete from database")?;
+    if num_deleted > 0 {
+        return Ok(None);
+    }
+
+    let mut records: Vec<UploadInfo> = upload_links::table
+        .filter(upload_links::uuid.eq(uuid.to_string()))
+        .filter(upload_links::user_id.eq(user_id))
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    let upload_info = records.pop().context("the link does not exist")?;
+    diesel::delete(upload_links::table.filter(upload_links::uuid.eq(&upload_info.uuid)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(Some(upload_info))
+}
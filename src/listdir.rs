@@ -1,8 +1,13 @@
 use anyhow::Context;
+use diesel::SqliteConnection;
 use futures_util::StreamExt;
 use serde::Serialize;
 
-use crate::safe_path::normalize_web_path_as_file;
+use crate::{
+    acl::Permission,
+    home::{UserContext, SHARED_PREFIX},
+    safe_path::normalize_web_path,
+};
 
 #[derive(Serialize)]
 pub struct DirEntry {
@@ -11,13 +16,26 @@ pub struct DirEntry {
     size: Option<u64>,
 }
 
-pub async fn list_dir(web_path: &str) -> anyhow::Result<Vec<DirEntry>> {
-    let normalized = normalize_web_path_as_file(web_path)?;
+pub async fn list_dir(
+    ctx: &UserContext,
+    web_path: &str,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Vec<DirEntry>> {
+    let normalized = ctx.authorize(web_path, Permission::Read, db)?;
     let mut readdir = async_std::fs::read_dir(normalized)
         .await
         .context("opendir")?;
     let mut result = Vec::new();
 
+    // The shared area shows up as a directory in the home directory
+    if ctx.has_shared_area() && normalize_web_path(web_path)?.as_os_str().is_empty() {
+        result.push(DirEntry {
+            name: SHARED_PREFIX.to_owned(),
+            directory: true,
+            size: None,
+        });
+    }
+
     while let Some(entry) = readdir.next().await {
         let entry = entry.context("readdir")?;
         let metadata = entry.metadata().await.context("read directory entry")?;
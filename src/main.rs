@@ -1,13 +1,26 @@
+mod acl;
+mod admin;
 mod api;
+mod archive;
+mod checksum;
 mod control;
 mod db;
 mod file_ops;
+mod gc;
+mod group;
+mod heartbeat;
+mod home;
+mod invite;
+mod links;
 mod listdir;
 mod models;
+mod range_set;
 mod safe_path;
 mod schema;
+mod settings;
 mod state;
 mod tls;
+mod trash;
 mod user;
 
 use actix_web::{middleware::Logger, App, HttpServer};
@@ -17,7 +30,12 @@ async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
 
     let app_state = actix_web::web::Data::new(state::AppState::new());
-    // TODO: add GC for app_state
+    home::migrate_legacy_files(&mut app_state.db.get().expect("obtain database connection"))
+        .expect("move files from before home directories");
+    if app_state.settings.shared_area {
+        home::create_shared_area().expect("create shared area");
+    }
+    actix_web::rt::spawn(gc::run(app_state.clone()));
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
@@ -1,10 +1,14 @@
 use diesel::{Insertable, Queryable};
+use serde::Serialize;
 
 #[derive(Queryable)]
 pub struct User {
     pub id: i32,
     pub username: String,
     pub hashed_pass: Option<String>,
+    /// As in [`crate::user::Role::as_str`].
+    pub role: String,
+    pub disabled: bool,
 }
 
 #[derive(Insertable)]
@@ -12,4 +16,119 @@ pub struct User {
 pub struct NewUser<'a> {
     pub username: &'a str,
     pub hashed_pass: &'a str,
+    pub role: &'a str,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct TrashItem {
+    pub id: i32,
+    #[serde(skip)]
+    pub user_id: i32,
+    #[serde(rename = "path")]
+    pub web_path: String,
+    #[serde(skip)]
+    pub stored_name: String,
+    pub deleted_at: i64,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::trash)]
+pub struct NewTrashItem<'a> {
+    pub user_id: i32,
+    pub web_path: &'a str,
+    pub stored_name: &'a str,
+    pub deleted_at: i64,
+}
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = crate::schema::download_links)]
+pub struct DownloadInfo {
+    pub uuid: String,
+    pub user_id: i32,
+    pub file: String,
+    pub expires: i64,
+    pub hashed_pass: Option<String>,
+    pub max_downloads: Option<i32>,
+    pub num_downloads: i32,
+    /// Archive format, if `file` is to be served as an archive.
+    pub archive: Option<String>,
+    /// JSON array of the selected paths, if only these entries of `file` are
+    /// to be archived.
+    pub entries: Option<String>,
+}
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = crate::schema::upload_links)]
+pub struct UploadInfo {
+    pub uuid: String,
+    pub user_id: i32,
+    pub file: String,
+    pub size: i64,
+    /// Whether a client has connected and created the staged file.
+    pub started: bool,
+    pub finished: bool,
+    pub expires: i64,
+    /// Expected checksum of the whole file, as in [`crate::checksum::Checksum::to_stored`].
+    pub checksum: Option<String>,
+    /// JSON array of the byte ranges already written to the staged file.
+    pub received: String,
+}
+
+#[derive(Queryable)]
+pub struct AclEntry {
+    #[allow(dead_code)]
+    pub id: i32,
+    pub owner_id: i32,
+    /// Normalized web path relative to the owner's home directory.
+    pub path: String,
+    pub user_id: i32,
+    /// Bit mask of [`crate::acl::Permission`]s.
+    pub permissions: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::acl)]
+pub struct NewAclEntry<'a> {
+    pub owner_id: i32,
+    pub path: &'a str,
+    pub user_id: i32,
+    pub permissions: i32,
+}
+
+#[derive(Queryable)]
+pub struct GroupAclEntry {
+    #[allow(dead_code)]
+    pub id: i32,
+    pub owner_id: i32,
+    /// Normalized web path relative to the owner's home directory.
+    pub path: String,
+    pub group_id: i32,
+    /// Bit mask of [`crate::acl::Permission`]s.
+    pub permissions: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::group_acl)]
+pub struct NewGroupAclEntry<'a> {
+    pub owner_id: i32,
+    pub path: &'a str,
+    pub group_id: i32,
+    pub permissions: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::schema::group_members)]
+pub struct GroupMember {
+    pub group_id: i32,
+    pub user_id: i32,
+}
+
+#[derive(Queryable, Insertable)]
+#[diesel(table_name = crate::schema::invites)]
+pub struct Invite {
+    pub code: String,
+    pub created_by: i32,
+    pub max_uses: i32,
+    pub num_uses: i32,
+    pub expires: i64,
 }
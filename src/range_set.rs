@@ -0,0 +1,99 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// A set of byte ranges, kept sorted and merged.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RangeSet {
+    ranges: Vec<Range<u64>>,
+}
+
+impl RangeSet {
+    pub fn new() -> RangeSet {
+        RangeSet::default()
+    }
+
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        // Find all ranges overlapping or adjacent to the new one, and replace
+        // them with their union
+        let start = self.ranges.partition_point(|r| r.end < range.start);
+        let end = self.ranges.partition_point(|r| r.start <= range.end);
+        let merged = if start < end {
+            self.ranges[start].start.min(range.start)..self.ranges[end - 1].end.max(range.end)
+        } else {
+            range
+        };
+        self.ranges.splice(start..end, [merged]);
+    }
+
+    /// Returns the ranges within `0..size` not covered by the set.
+    pub fn missing(&self, size: u64) -> Vec<Range<u64>> {
+        let mut result = Vec::new();
+        let mut pos = 0;
+        for r in &self.ranges {
+            if r.start >= size {
+                break;
+            }
+            if r.start > pos {
+                result.push(pos..r.start);
+            }
+            pos = pos.max(r.end);
+        }
+        if pos < size {
+            result.push(pos..size);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RangeSet;
+
+    fn ranges(set: &RangeSet) -> Vec<(u64, u64)> {
+        set.ranges.iter().map(|r| (r.start, r.end)).collect()
+    }
+
+    fn missing(set: &RangeSet, size: u64) -> Vec<(u64, u64)> {
+        set.missing(size).iter().map(|r| (r.start, r.end)).collect()
+    }
+
+    #[test]
+    fn insert_merges_overlapping_and_adjacent() {
+        let mut set = RangeSet::new();
+        set.insert(10..20);
+        set.insert(30..40);
+        assert_eq!(ranges(&set), [(10, 20), (30, 40)]);
+        set.insert(20..25);
+        assert_eq!(ranges(&set), [(10, 25), (30, 40)]);
+        set.insert(5..35);
+        assert_eq!(ranges(&set), [(5, 40)]);
+        set.insert(0..0);
+        assert_eq!(ranges(&set), [(5, 40)]);
+    }
+
+    #[test]
+    fn insert_keeps_ranges_sorted() {
+        let mut set = RangeSet::new();
+        set.insert(50..60);
+        set.insert(0..10);
+        set.insert(20..30);
+        assert_eq!(ranges(&set), [(0, 10), (20, 30), (50, 60)]);
+    }
+
+    #[test]
+    fn missing_returns_gaps() {
+        let mut set = RangeSet::new();
+        assert_eq!(missing(&set, 100), [(0, 100)]);
+        set.insert(10..20);
+        set.insert(90..120);
+        assert_eq!(missing(&set, 100), [(0, 10), (20, 90)]);
+        set.insert(0..90);
+        assert!(set.missing(100).is_empty());
+    }
+}
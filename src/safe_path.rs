@@ -3,7 +3,7 @@ use std::path::{Path, PathBuf};
 const ILLEGAL_CHARS: &str = "\\/:*?\"<>|";
 const LENGTH_LIMIT: usize = 2 << 10; // 2 KiB
 
-fn ensure_legal_segment(segment: &str) -> anyhow::Result<()> {
+pub fn ensure_legal_segment(segment: &str) -> anyhow::Result<()> {
     for (i, ch) in segment.chars().enumerate() {
         if ILLEGAL_CHARS.find(ch).is_some() {
             anyhow::bail!("illegal character \"{ch}\" at position {i} of segment {segment:?}");
@@ -38,6 +38,11 @@ pub fn normalize_web_path(path: &str) -> anyhow::Result<PathBuf> {
     Ok(result)
 }
 
-pub fn normalize_web_path_as_file(path: &str) -> anyhow::Result<PathBuf> {
-    normalize_web_path(path).map(|path| Path::new("files").join(path))
+/// Converts a normalized relative path back to its web form.
+pub fn to_web_path(relative: &Path) -> String {
+    relative
+        .iter()
+        .map(|segment| segment.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
 }
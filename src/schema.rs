@@ -1,9 +1,114 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    acl (id) {
+        id -> Integer,
+        owner_id -> Integer,
+        path -> Text,
+        user_id -> Integer,
+        permissions -> Integer,
+    }
+}
+
+diesel::table! {
+    download_links (uuid) {
+        uuid -> Text,
+        user_id -> Integer,
+        file -> Text,
+        expires -> BigInt,
+        hashed_pass -> Nullable<Text>,
+        max_downloads -> Nullable<Integer>,
+        num_downloads -> Integer,
+        archive -> Nullable<Text>,
+        entries -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    group_acl (id) {
+        id -> Integer,
+        owner_id -> Integer,
+        path -> Text,
+        group_id -> Integer,
+        permissions -> Integer,
+    }
+}
+
+diesel::table! {
+    group_members (group_id, user_id) {
+        group_id -> Integer,
+        user_id -> Integer,
+    }
+}
+
+diesel::table! {
+    groups (id) {
+        id -> Integer,
+        name -> Text,
+    }
+}
+
+diesel::table! {
+    invites (code) {
+        code -> Text,
+        created_by -> Integer,
+        max_uses -> Integer,
+        num_uses -> Integer,
+        expires -> BigInt,
+    }
+}
+
+diesel::table! {
+    trash (id) {
+        id -> Integer,
+        user_id -> Integer,
+        web_path -> Text,
+        stored_name -> Text,
+        deleted_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    upload_links (uuid) {
+        uuid -> Text,
+        user_id -> Integer,
+        file -> Text,
+        size -> BigInt,
+        started -> Bool,
+        finished -> Bool,
+        expires -> BigInt,
+        checksum -> Nullable<Text>,
+        received -> Text,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Integer,
         username -> Text,
         hashed_pass -> Nullable<Text>,
+        role -> Text,
+        disabled -> Bool,
     }
 }
+
+diesel::joinable!(download_links -> users (user_id));
+diesel::joinable!(group_acl -> groups (group_id));
+diesel::joinable!(group_acl -> users (owner_id));
+diesel::joinable!(group_members -> groups (group_id));
+diesel::joinable!(group_members -> users (user_id));
+diesel::joinable!(invites -> users (created_by));
+diesel::joinable!(trash -> users (user_id));
+diesel::joinable!(upload_links -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    acl,
+    download_links,
+    group_acl,
+    group_members,
+    groups,
+    invites,
+    trash,
+    upload_links,
+    users,
+);
@@ -0,0 +1,63 @@
+use anyhow::Context;
+use serde::Deserialize;
+
+const SETTINGS_FILE: &str = "config/settings.json";
+
+/// Who may create an account with `RegisterPwd`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Registration {
+    /// Anybody who can reach the server.
+    #[default]
+    Open,
+    /// Only those with a valid invite code.
+    InviteOnly,
+    /// Nobody, except for the first user who becomes the administrator.
+    Closed,
+}
+
+/// What passwords are acceptable when they are set.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordPolicy {
+    /// Minimum length in characters.
+    pub min_length: usize,
+    /// Whether to reject a built-in list of very common passwords.
+    pub deny_common: bool,
+    /// More passwords to reject, compared case-insensitively.
+    pub denied: Vec<String>,
+    /// Whether to reject passwords that contain the username or are contained
+    /// in it, unless the shorter of the two is very short.
+    pub deny_username: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            deny_common: true,
+            denied: Vec::new(),
+            deny_username: true,
+        }
+    }
+}
+
+/// Server-wide options, read from `config/settings.json` if it exists.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Whether all users can reach a common area as `@shared`.
+    pub shared_area: bool,
+    pub registration: Registration,
+    pub password_policy: PasswordPolicy,
+}
+
+impl Settings {
+    pub fn load() -> anyhow::Result<Settings> {
+        match std::fs::read_to_string(SETTINGS_FILE) {
+            Ok(json) => serde_json::from_str(&json).context("parse config/settings.json"),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Settings::default()),
+            Err(err) => Err(err).context("read config/settings.json"),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use futures_util::StreamExt;
+
+use crate::{
+    acl::Permission,
+    file_ops::DeleteFailure,
+    home::UserContext,
+    models::{NewTrashItem, TrashItem},
+    safe_path::{normalize_web_path, to_web_path},
+};
+
+/// How long deleted entries are kept before the GC purges them.
+pub const RETENTION_SECS: i64 = 30 * 24 * 60 * 60;
+
+fn trash_dir(user_id: i32) -> PathBuf {
+    Path::new("trash").join(user_id.to_string())
+}
+
+async fn remove_entry(path: &Path) -> std::io::Result<()> {
+    if async_std::fs::symlink_metadata(path).await?.is_dir() {
+        async_std::fs::remove_dir_all(path).await
+    } else {
+        async_std::fs::remove_file(path).await
+    }
+}
+
+/// Moves an entry into the trash instead of deleting it.
+///
+/// Entries in a home directory go to the owner's trash, even if somebody who
+/// was granted access deleted them. Those in the shared area go to the trash
+/// of whoever deleted them. What was granted on them is deleted right away.
+pub async fn move_to_trash(
+    ctx: &UserContext,
+    web_path: &str,
+    recursive: bool,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    let location = crate::file_ops::locate_removable(ctx, web_path, db)?;
+    let normalized = location.path();
+
+    // Store the normalized web path as the owner sees it, so that restoring
+    // does not depend on how the client spelled it
+    let (user_id, web_path) = match location.owner {
+        Some(owner) => (owner, to_web_path(&location.relative)),
+        None => (ctx.user_id, to_web_path(&normalize_web_path(web_path)?)),
+    };
+
+    let metadata = async_std::fs::symlink_metadata(&normalized)
+        .await
+        .context("the path specified does not exist")?;
+    if metadata.is_dir() && !recursive {
+        let mut readdir = async_std::fs::read_dir(&normalized)
+            .await
+            .context("opendir")?;
+        anyhow::ensure!(
+            readdir.next().await.is_none(),
+            "directory is not empty (use recursive mode for non-empty directories)",
+        );
+    }
+
+    let dir = trash_dir(user_id);
+    async_std::fs::create_dir_all(&dir)
+        .await
+        .context("create trash directory")?;
+    let stored_name = uuid::Builder::from_random_bytes(rand::random())
+        .into_uuid()
+        .to_string();
+    let stored = dir.join(&stored_name);
+    async_std::fs::rename(&normalized, &stored)
+        .await
+        .context("move into trash")?;
+
+    let result = diesel::insert_into(crate::schema::trash::table)
+        .values(&NewTrashItem {
+            user_id,
+            web_path: &web_path,
+            stored_name: &stored_name,
+            deleted_at: crate::db::now(),
+        })
+        .execute(db)
+        .context("insert into database");
+    if result.is_err() {
+        // Put the entry back, otherwise it would be lost in the trash
+        if let Err(err) = async_std::fs::rename(&stored, &normalized).await {
+            log::error!("Failed to move {stored:?} back to {normalized:?}: {err}");
+        }
+    }
+    result?;
+    log::debug!("Moved {normalized:?} into trash as {stored:?}");
+
+    // Restoring it does not bring back what was granted on it
+    if let Some(owner) = location.owner {
+        crate::acl::delete_grants(owner, &location.relative, db)?;
+    }
+    Ok(())
+}
+
+pub fn list(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<Vec<TrashItem>> {
+    use crate::schema::trash::dsl;
+
+    dsl::trash
+        .filter(dsl::user_id.eq(user_id))
+        .order(dsl::deleted_at.desc())
+        .load(db)
+        .context("query database")
+}
+
+fn find(user_id: i32, id: i32, db: &mut SqliteConnection) -> anyhow::Result<TrashItem> {
+    use crate::schema::trash::dsl;
+
+    let mut records: Vec<TrashItem> = dsl::trash
+        .filter(dsl::id.eq(id))
+        .filter(dsl::user_id.eq(user_id))
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    records.pop().context("the trash item does not exist")
+}
+
+/// Moves a trash item back to its original location, or to `web_path` if
+/// specified.
+pub async fn restore(
+    ctx: &UserContext,
+    id: i32,
+    web_path: Option<&str>,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    use crate::schema::trash::dsl;
+
+    let user_id = ctx.user_id;
+    let item = find(user_id, id, db)?;
+    let web_path = web_path.unwrap_or(&item.web_path);
+    let location = ctx.locate_authorized(web_path, Permission::Upload, db)?;
+    anyhow::ensure!(!location.is_root(), "cannot restore to the root directory");
+    let normalized = location.path();
+
+    anyhow::ensure!(
+        async_std::path::Path::new(normalized.parent().unwrap())
+            .is_dir()
+            .await,
+        "the parent directory of {web_path:?} does not exist",
+    );
+    anyhow::ensure!(
+        async_std::fs::symlink_metadata(&normalized).await.is_err(),
+        "{web_path:?} already exists",
+    );
+
+    async_std::fs::rename(trash_dir(user_id).join(&item.stored_name), &normalized)
+        .await
+        .context("move out of trash")?;
+    diesel::delete(dsl::trash.filter(dsl::id.eq(item.id)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(())
+}
+
+async fn purge_items(
+    items: Vec<TrashItem>,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Vec<DeleteFailure>> {
+    use crate::schema::trash::dsl;
+
+    let mut failed = Vec::new();
+    for item in items {
+        let stored = trash_dir(item.user_id).join(&item.stored_name);
+        match remove_entry(&stored).await {
+            Ok(()) => (),
+            // Already gone, so only the record is left to clean up
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => {
+                log::debug!("Failed to purge {stored:?}: {err}");
+                failed.push(DeleteFailure {
+                    path: item.web_path,
+                    err: err.to_string(),
+                });
+                continue;
+            }
+        }
+        diesel::delete(dsl::trash.filter(dsl::id.eq(item.id)))
+            .execute(db)
+            .context("delete from database")?;
+    }
+    Ok(failed)
+}
+
+/// Permanently deletes one trash item, or the whole trash if `id` is `None`.
+pub async fn purge(
+    user_id: i32,
+    id: Option<i32>,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<Vec<DeleteFailure>> {
+    let items = match id {
+        Some(id) => vec![find(user_id, id, db)?],
+        None => list(user_id, db)?,
+    };
+    purge_items(items, db).await
+}
+
+/// Permanently deletes the whole trash of a user whose account is going away.
+pub async fn remove_all(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::trash::dsl;
+
+    match async_std::fs::remove_dir_all(trash_dir(user_id)).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(err).context("remove trash directory");
+        }
+        _ => (),
+    }
+    diesel::delete(dsl::trash.filter(dsl::user_id.eq(user_id)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(())
+}
+
+/// Permanently deletes all trash items older than [`RETENTION_SECS`],
+/// returning the number of items purged.
+pub async fn purge_expired(db: &mut SqliteConnection) -> anyhow::Result<usize> {
+    use crate::schema::trash::dsl;
+
+    let items: Vec<TrashItem> = dsl::trash
+        .filter(dsl::deleted_at.lt(crate::db::now() - RETENTION_SECS))
+        .load(db)
+        .context("query database")?;
+    let num_items = items.len();
+    let failed = purge_items(items, db).await?;
+    for failure in &failed {
+        log::warn!(
+            "Failed to purge trash item {:?}: {}",
+            failure.path,
+            failure.err
+        );
+    }
+    Ok(num_items - failed.len())
+}
@@ -1,23 +1,100 @@
 use anyhow::Context;
 use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use serde::{Deserialize, Serialize};
 use sodiumoxide::crypto::pwhash::argon2id13 as sodium;
 
-use crate::models::{NewUser, User};
+use crate::{
+    models::{NewUser, User},
+    settings::{PasswordPolicy, Registration, Settings},
+};
+
+/// What a user may do beyond managing their own files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    /// Manages users and groups.
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        match s {
+            "user" => Some(Role::User),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// A user as shown to administrators.
+#[derive(Serialize)]
+pub struct UserEntry {
+    pub username: String,
+    pub role: Role,
+    pub disabled: bool,
+}
 
 const OPS_LIMIT: sodium::OpsLimit = sodium::OPSLIMIT_INTERACTIVE;
 const MEM_LIMIT: sodium::MemLimit = sodium::MemLimit(4 << 20);
 
-fn pwhash(password: &str) -> sodium::HashedPassword {
+/// Usernames and passwords shorter than this are not compared with each other
+/// by [`PasswordPolicy::deny_username`], as they turn up inside too many
+/// unrelated words.
+const MIN_RESEMBLANCE_LENGTH: usize = 4;
+
+/// Passwords rejected by [`PasswordPolicy::deny_common`], in lowercase.
+const COMMON_PASSWORDS: &[&str] = &[
+    "123456",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "111111",
+    "000000",
+    "password",
+    "password1",
+    "passw0rd",
+    "qwerty",
+    "qwertyuiop",
+    "qwerty123",
+    "abc123",
+    "abcdefgh",
+    "letmein",
+    "welcome",
+    "iloveyou",
+    "admin",
+    "administrator",
+    "monkey",
+    "dragon",
+    "football",
+    "baseball",
+    "sunshine",
+    "princess",
+    "trustno1",
+    "superman",
+    "starwars",
+    "changeme",
+    "secret",
+];
+
+pub fn pwhash(password: &str) -> sodium::HashedPassword {
     sodium::pwhash(password.as_bytes(), OPS_LIMIT, MEM_LIMIT).expect("cannot allocate memory")
 }
 
-fn pwhash_as_str(pwhash: &sodium::HashedPassword) -> &str {
+pub fn pwhash_as_str(pwhash: &sodium::HashedPassword) -> &str {
     std::str::from_utf8(&pwhash.0)
         .unwrap()
         .trim_end_matches(0 as char)
 }
 
-fn pwhash_verify(plain_pass: &str, hashed_pass: &str) -> anyhow::Result<()> {
+pub fn pwhash_verify(plain_pass: &str, hashed_pass: &str) -> anyhow::Result<()> {
     let mut hashed_pass_sodium = sodium::HashedPassword([0; sodium::HASHEDPASSWORDBYTES]);
     hashed_pass_sodium.0[..hashed_pass.len()].copy_from_slice(hashed_pass.as_bytes());
 
@@ -28,6 +105,60 @@ fn pwhash_verify(plain_pass: &str, hashed_pass: &str) -> anyhow::Result<()> {
     }
 }
 
+/// Checks that a new username can appear in web paths after
+/// [`HOME_PREFIX`](crate::home::HOME_PREFIX), and name the directory that the
+/// user's files are transferred into when the account is deleted.
+pub fn check_username(input_username: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        input_username.chars().count() <= 64,
+        "username must be at most 64 characters long",
+    );
+    anyhow::ensure!(
+        input_username.trim() == input_username,
+        "username must not start or end with whitespace",
+    );
+    anyhow::ensure!(
+        !input_username.chars().any(char::is_control),
+        "username must not contain control characters",
+    );
+    crate::home::ensure_top_level_name(input_username)
+}
+
+/// Checks a new password against `policy`.
+pub fn check_password(
+    input_username: &str,
+    password: &str,
+    policy: &PasswordPolicy,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(!password.is_empty(), "password must not be empty");
+    anyhow::ensure!(
+        password.chars().count() >= policy.min_length,
+        "password must be at least {} characters long",
+        policy.min_length,
+    );
+
+    let lowercase = password.to_lowercase();
+    let common = policy.deny_common && COMMON_PASSWORDS.contains(&lowercase.as_str());
+    let denied = policy
+        .denied
+        .iter()
+        .any(|denied| denied.to_lowercase() == lowercase);
+    anyhow::ensure!(!common && !denied, "password is too common");
+
+    if policy.deny_username {
+        let username = input_username.to_lowercase();
+        let contains_username =
+            username.chars().count() >= MIN_RESEMBLANCE_LENGTH && lowercase.contains(&username);
+        let in_username =
+            lowercase.chars().count() >= MIN_RESEMBLANCE_LENGTH && username.contains(&lowercase);
+        anyhow::ensure!(
+            !contains_username && !in_username,
+            "password must not resemble the username",
+        );
+    }
+    Ok(())
+}
+
 pub fn login(
     input_username: &str,
     input_password: &str,
@@ -56,50 +187,324 @@ pub fn login(
             .context("the user has disabled password authentication")?,
     )
     .context("verify password")?;
+    anyhow::ensure!(!records[0].disabled, "the account has been disabled");
 
     Ok(records[0].id)
 }
 
+/// Creates an account if the registration policy allows it, consuming
+/// `invite` if one is needed.
 pub fn register(
     input_username: &str,
     input_password: &str,
+    invite: Option<&str>,
+    settings: &Settings,
     db: &mut SqliteConnection,
 ) -> anyhow::Result<i32> {
     use crate::schema::users::dsl::*;
 
-    // Ensure that the user did not exist (as a dual fail-safe)
-    let records: Vec<User> = users
+    check_username(input_username)?;
+    check_password(input_username, input_password, &settings.password_policy)?;
+    let input_pass_hashed = pwhash(input_password);
+
+    // Decide on the role, count the invite and insert the user together, so
+    // that parallel registrations cannot all become the first user or use an
+    // invite that then fails to produce an account
+    db.immediate_transaction(|db| {
+        // Ensure that the user did not exist (as a dual fail-safe)
+        let records: Vec<User> = users
+            .filter(username.eq(input_username))
+            .limit(1)
+            .load(db)
+            .context("query database")?;
+        anyhow::ensure!(
+            records.is_empty(),
+            "user {:?} already exists",
+            input_username,
+        );
+
+        // The very first user becomes an administrator regardless of the
+        // policy, so that somebody can manage the server
+        let new_role = if find_first(db)?.is_none() {
+            Role::Admin
+        } else {
+            match settings.registration {
+                Registration::Open => (),
+                Registration::InviteOnly => {
+                    crate::invite::consume(invite.context("an invite code is required")?, db)?
+                }
+                Registration::Closed => anyhow::bail!("registration is closed"),
+            }
+            Role::User
+        };
+
+        // Insert into the database
+        let user = NewUser {
+            username: input_username,
+            hashed_pass: pwhash_as_str(&input_pass_hashed),
+            role: new_role.as_str(),
+        };
+        // SQLite does not support SQL `RETURNING` clauses, so we have to
+        // manually query the newly created user's ID.
+        // https://stackoverflow.com/questions/65437001
+        diesel::insert_into(users)
+            .values(&user)
+            .execute(db)
+            .context("insert into database")?;
+
+        // Query user ID
+        let records: Vec<User> = users
+            .filter(username.eq(input_username))
+            .limit(1)
+            .load(db)
+            .context("query database")?;
+        anyhow::ensure!(records.len() == 1, "cannot insert into database");
+
+        Ok(records[0].id)
+    })
+}
+
+pub fn find_id(input_username: &str, db: &mut SqliteConnection) -> anyhow::Result<i32> {
+    lookup_id(input_username, db)?
+        .with_context(|| format!("user {input_username:?} does not exist"))
+}
+
+/// Looks up the ID of a user, who may not exist.
+pub fn lookup_id(input_username: &str, db: &mut SqliteConnection) -> anyhow::Result<Option<i32>> {
+    use crate::schema::users::dsl::*;
+
+    let mut records: Vec<i32> = users
         .filter(username.eq(input_username))
+        .select(id)
         .limit(1)
         .load(db)
         .context("query database")?;
-    anyhow::ensure!(
-        records.is_empty(),
-        "user {:?} already exists",
-        input_username,
-    );
+    Ok(records.pop())
+}
 
-    // Insert into the database
-    let input_pass_hashed = pwhash(input_password);
-    let user = NewUser {
-        username: input_username,
-        hashed_pass: pwhash_as_str(&input_pass_hashed),
-    };
-    // SQLite does not support SQL `RETURNING` clauses, so we have to manually
-    // query the newly created user's ID.
-    // https://stackoverflow.com/questions/65437001
-    diesel::insert_into(users)
-        .values(&user)
+pub fn find_username(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<String> {
+    use crate::schema::users::dsl::*;
+
+    let mut records: Vec<String> = users
+        .filter(id.eq(user_id))
+        .select(username)
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    records.pop().context("the user does not exist")
+}
+
+/// Looks up the ID of the oldest account, if there is one.
+pub fn find_first(db: &mut SqliteConnection) -> anyhow::Result<Option<i32>> {
+    use crate::schema::users::dsl::*;
+
+    let mut records: Vec<i32> = users
+        .select(id)
+        .order(id)
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    Ok(records.pop())
+}
+
+/// Looks up the role of a user who may still use the server.
+pub fn find_active_role(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<Role> {
+    use crate::schema::users::dsl::*;
+
+    let mut records: Vec<User> = users
+        .filter(id.eq(user_id))
+        .limit(1)
+        .load(db)
+        .context("query database")?;
+    let user = records.pop().context("the user does not exist")?;
+    anyhow::ensure!(!user.disabled, "the account has been disabled");
+    Role::parse(&user.role).with_context(|| format!("unknown role {:?}", user.role))
+}
+
+pub fn list(db: &mut SqliteConnection) -> anyhow::Result<Vec<UserEntry>> {
+    use crate::schema::users::dsl::*;
+
+    let records: Vec<User> = users.order(username).load(db).context("query database")?;
+    records
+        .into_iter()
+        .map(|user| {
+            Ok(UserEntry {
+                role: Role::parse(&user.role)
+                    .with_context(|| format!("unknown role {:?}", user.role))?,
+                username: user.username,
+                disabled: user.disabled,
+            })
+        })
+        .collect()
+}
+
+/// Changes the role of a user, as long as some other administrator remains.
+pub fn set_role(user_id: i32, new_role: Role, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::users::dsl::*;
+
+    if new_role != Role::Admin {
+        let num_others: i64 = users
+            .filter(role.eq(Role::Admin.as_str()))
+            .filter(disabled.eq(false))
+            .filter(id.ne(user_id))
+            .count()
+            .get_result(db)
+            .context("query database")?;
+        anyhow::ensure!(num_others > 0, "cannot demote the last administrator");
+    }
+    diesel::update(users.filter(id.eq(user_id)))
+        .set(role.eq(new_role.as_str()))
         .execute(db)
-        .context("insert into database")?;
+        .context("update database")?;
+    Ok(())
+}
 
-    // Query user ID
-    let records: Vec<User> = users
-        .filter(username.eq(input_username))
+pub fn set_disabled(user_id: i32, value: bool, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::users::dsl::*;
+
+    diesel::update(users.filter(id.eq(user_id)))
+        .set(disabled.eq(value))
+        .execute(db)
+        .context("update database")?;
+    Ok(())
+}
+
+/// Replaces the password of a user, if the new one satisfies `policy`.
+pub fn set_password(
+    user_id: i32,
+    new_password: &str,
+    policy: &PasswordPolicy,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    use crate::schema::users::dsl::*;
+
+    check_password(&find_username(user_id, db)?, new_password, policy)?;
+    diesel::update(users.filter(id.eq(user_id)))
+        .set(hashed_pass.eq(pwhash_as_str(&pwhash(new_password))))
+        .execute(db)
+        .context("update database")?;
+    Ok(())
+}
+
+/// Lets a user replace their password after proving that they know it.
+pub fn change_password(
+    user_id: i32,
+    old_password: &str,
+    new_password: &str,
+    policy: &PasswordPolicy,
+    db: &mut SqliteConnection,
+) -> anyhow::Result<()> {
+    use crate::schema::users::dsl::*;
+
+    let mut records: Vec<User> = users
+        .filter(id.eq(user_id))
         .limit(1)
         .load(db)
         .context("query database")?;
-    anyhow::ensure!(records.len() == 1, "cannot insert into database");
+    let user = records.pop().context("the user does not exist")?;
+    pwhash_verify(
+        old_password,
+        user.hashed_pass
+            .as_ref()
+            .context("the user has disabled password authentication")?,
+    )
+    .context("verify password")?;
+    anyhow::ensure!(
+        old_password != new_password,
+        "the new password must differ from the old one",
+    );
+    set_password(user_id, new_password, policy, db)
+}
 
-    Ok(records[0].id)
+/// Deletes the account itself, after everything referring to it is gone.
+pub fn delete(user_id: i32, db: &mut SqliteConnection) -> anyhow::Result<()> {
+    use crate::schema::users::dsl::*;
+
+    diesel::delete(users.filter(id.eq(user_id)))
+        .execute(db)
+        .context("delete from database")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_password, check_username};
+    use crate::settings::PasswordPolicy;
+
+    #[test]
+    fn check_password_enforces_length() {
+        let policy = PasswordPolicy::default();
+        assert!(check_password("alice", "", &policy).is_err());
+        assert!(check_password("alice", "plum-72", &policy).is_err());
+        assert!(check_password("alice", "plum-721", &policy).is_ok());
+        // Characters are counted, not bytes
+        let policy = PasswordPolicy {
+            min_length: 4,
+            ..PasswordPolicy::default()
+        };
+        assert!(check_password("alice", "äöü", &policy).is_err());
+        assert!(check_password("alice", "äöüß", &policy).is_ok());
+    }
+
+    #[test]
+    fn check_password_rejects_common_and_denied() {
+        let policy = PasswordPolicy {
+            denied: vec!["Company2024".to_owned()],
+            ..PasswordPolicy::default()
+        };
+        assert!(check_password("alice", "Password", &policy).is_err());
+        assert!(check_password("alice", "company2024", &policy).is_err());
+        assert!(check_password("alice", "company2025", &policy).is_ok());
+
+        let policy = PasswordPolicy {
+            deny_common: false,
+            ..PasswordPolicy::default()
+        };
+        assert!(check_password("alice", "password", &policy).is_ok());
+    }
+
+    #[test]
+    fn check_password_rejects_username() {
+        let policy = PasswordPolicy::default();
+        assert!(check_password("alice", "Alice1234", &policy).is_err());
+        assert!(check_password("christopher", "christop", &policy).is_err());
+        assert!(check_password("alice", "malice-aforethought", &policy).is_err());
+
+        let policy = PasswordPolicy {
+            deny_username: false,
+            ..PasswordPolicy::default()
+        };
+        assert!(check_password("alice", "Alice1234", &policy).is_ok());
+    }
+
+    #[test]
+    fn check_password_ignores_short_usernames() {
+        let policy = PasswordPolicy::default();
+        assert!(check_password("al", "metal-bloom", &policy).is_ok());
+        assert!(check_password("bob", "bobsleigh-run", &policy).is_ok());
+        assert!(check_password("x", "xylophone", &policy).is_ok());
+
+        // Nor are short passwords compared with long usernames
+        let policy = PasswordPolicy {
+            min_length: 3,
+            ..PasswordPolicy::default()
+        };
+        assert!(check_password("antonio", "ton", &policy).is_ok());
+    }
+
+    #[test]
+    fn check_username_rejects_unusable_names() {
+        assert!(check_username("alice").is_ok());
+        assert!(check_username("Zoë O'Brien").is_ok());
+        assert!(check_username("").is_err());
+        assert!(check_username(".").is_err());
+        assert!(check_username("..").is_err());
+        assert!(check_username("a/b").is_err());
+        assert!(check_username("a\\b").is_err());
+        assert!(check_username("~alice").is_err());
+        assert!(check_username("@shared").is_err());
+        assert!(check_username(" alice").is_err());
+        assert!(check_username("al\nice").is_err());
+        assert!(check_username(&"a".repeat(65)).is_err());
+    }
 }